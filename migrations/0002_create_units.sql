@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS units (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    inserted_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL
+);
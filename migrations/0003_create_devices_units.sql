@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS devices_units (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    device_id BIGINT UNSIGNED NOT NULL,
+    unit_id BIGINT UNSIGNED NOT NULL,
+    inserted_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    FOREIGN KEY (device_id) REFERENCES devices (id) ON DELETE CASCADE,
+    FOREIGN KEY (unit_id) REFERENCES units (id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+CREATE INDEX records_device_id_unit_id_inserted_at_index ON records (device_id, unit_id, inserted_at);
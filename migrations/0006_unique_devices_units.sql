@@ -0,0 +1,2 @@
+-- fails if devices_units has duplicated links
+CREATE UNIQUE INDEX devices_units_device_id_unit_id_index ON devices_units (device_id, unit_id);
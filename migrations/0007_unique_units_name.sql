@@ -0,0 +1,2 @@
+-- fails if units has duplicated names, merge them by `ffeeder dedupe-units`
+CREATE UNIQUE INDEX units_name_index ON units (name);
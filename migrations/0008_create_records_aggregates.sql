@@ -0,0 +1,35 @@
+-- hourly and daily aggregates of records removed by retention
+CREATE TABLE IF NOT EXISTS records_hourly (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    device_id BIGINT UNSIGNED NOT NULL,
+    unit_id BIGINT UNSIGNED NOT NULL,
+    bucket DATETIME NOT NULL,
+    min_value DOUBLE,
+    max_value DOUBLE,
+    avg_value DOUBLE,
+    value_count BIGINT UNSIGNED NOT NULL,
+    last_value VARCHAR(255),
+    inserted_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    FOREIGN KEY (device_id) REFERENCES devices (id) ON DELETE CASCADE,
+    FOREIGN KEY (unit_id) REFERENCES units (id) ON DELETE CASCADE,
+    UNIQUE KEY records_hourly_device_id_unit_id_bucket_index (device_id, unit_id, bucket)
+);
+CREATE TABLE IF NOT EXISTS records_daily (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    device_id BIGINT UNSIGNED NOT NULL,
+    unit_id BIGINT UNSIGNED NOT NULL,
+    bucket DATETIME NOT NULL,
+    min_value DOUBLE,
+    max_value DOUBLE,
+    avg_value DOUBLE,
+    value_count BIGINT UNSIGNED NOT NULL,
+    last_value VARCHAR(255),
+    inserted_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    FOREIGN KEY (device_id) REFERENCES devices (id) ON DELETE CASCADE,
+    FOREIGN KEY (unit_id) REFERENCES units (id) ON DELETE CASCADE,
+    UNIQUE KEY records_daily_device_id_unit_id_bucket_index (device_id, unit_id, bucket)
+);
+-- retention selects and deletes records of a unit by time
+CREATE INDEX records_unit_id_inserted_at_index ON records (unit_id, inserted_at);
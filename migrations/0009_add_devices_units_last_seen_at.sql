@@ -0,0 +1,2 @@
+-- last time a value of the unit came from the device, written for units with a deadband
+ALTER TABLE devices_units ADD COLUMN last_seen_at DATETIME NULL;
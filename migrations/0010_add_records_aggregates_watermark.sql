@@ -0,0 +1,12 @@
+-- id of the last record rolled into a bucket, late records with a bigger id are merged into the bucket
+ALTER TABLE records_hourly ADD COLUMN last_record_id BIGINT UNSIGNED NOT NULL DEFAULT 0;
+ALTER TABLE records_hourly ADD COLUMN last_value_at DATETIME NULL;
+ALTER TABLE records_daily ADD COLUMN last_record_id BIGINT UNSIGNED NOT NULL DEFAULT 0;
+ALTER TABLE records_daily ADD COLUMN last_value_at DATETIME NULL;
+-- records left by an interrupted deletion are already counted in the existing buckets
+UPDATE records_hourly target SET last_record_id = (SELECT COALESCE(MAX(records.id), 0) FROM records
+    WHERE records.device_id = target.device_id AND records.unit_id = target.unit_id
+        AND records.inserted_at >= target.bucket AND records.inserted_at < target.bucket + INTERVAL 1 HOUR);
+UPDATE records_daily target SET last_record_id = (SELECT COALESCE(MAX(records.id), 0) FROM records
+    WHERE records.device_id = target.device_id AND records.unit_id = target.unit_id
+        AND records.inserted_at >= target.bucket AND records.inserted_at < target.bucket + INTERVAL 1 DAY);
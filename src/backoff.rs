@@ -0,0 +1,124 @@
+//** Reconnect policy */
+//** Exponential backoff with jitter, shared by MQTT, MySQL and Phoenix connections. */
+//** Attempts are counted in metrics: reconnect_attempts_total and reconnect_attempt (current streak) per connection */
+
+use std::thread;
+use std::time::Duration;
+use log::{info, warn};
+use rand::Rng;
+use serde::Deserialize;
+
+use super::metrics;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    /// Delay before the first retry, in milliseconds
+    pub initial_delay: u64,
+    /// Upper bound of the delay, in milliseconds
+    pub max_delay: u64,
+    pub multiplier: f64,
+    /// Part of the delay which is randomized: 0.0 - no jitter, 1.0 - delay is random in [0, delay]
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: 500,
+            max_delay: 60_000,
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Backoff {
+    connection: &'static str,
+    policy: ReconnectPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(connection: &'static str, policy: ReconnectPolicy) -> Self {
+        Backoff {
+            connection,
+            policy,
+            attempt: 0,
+        }
+    }
+
+    /// Number of failed attempts since the last success
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay for the current attempt without jitter
+    fn base_delay(&self) -> Duration {
+        let exponent = self.attempt.saturating_sub(1).min(32) as i32;
+        let delay = self.policy.initial_delay as f64 * self.policy.multiplier.powi(exponent);
+        Duration::from_millis(delay.min(self.policy.max_delay as f64) as u64)
+    }
+
+    /// Register a failed attempt and return the delay before the next one
+    pub fn next_delay(&mut self) -> Duration {
+        self.attempt += 1;
+        metrics::increment(&format!("reconnect_attempts_total{{connection=\"{}\"}}", self.connection));
+        metrics::set(&format!("reconnect_attempt{{connection=\"{}\"}}", self.connection), self.attempt as u64);
+
+        let delay = self.base_delay();
+        let jitter = self.policy.jitter.clamp(0.0, 1.0);
+        if jitter > 0.0 {
+            let randomized = delay.mul_f64(jitter * rand::thread_rng().gen::<f64>());
+            delay - randomized
+        } else {
+            delay
+        }
+    }
+
+    /// Register a failed attempt and sleep before the next one
+    pub fn wait(&mut self) {
+        let delay = self.next_delay();
+        warn!("Reconnect to {} in {:?} (attempt {})", self.connection, delay, self.attempt);
+        thread::sleep(delay);
+    }
+
+    /// Register a successful connection
+    pub fn reset(&mut self) {
+        if self.attempt > 0 {
+            info!("Reconnected to {} after {} attempts", self.connection, self.attempt);
+            metrics::increment(&format!("reconnects_total{{connection=\"{}\"}}", self.connection));
+        }
+        self.attempt = 0;
+        metrics::set(&format!("reconnect_attempt{{connection=\"{}\"}}", self.connection), 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy { initial_delay: 100, max_delay: 1000, multiplier: 2.0, jitter: 0.0 }
+    }
+
+    #[test]
+    fn test_exponential_delay_is_bounded() {
+        let mut backoff = Backoff::new("test", policy());
+        let delays: Vec<u128> = (0..6).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff.attempt(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay().as_millis(), 100);
+    }
+
+    #[test]
+    fn test_jitter_keeps_delay_in_range() {
+        let mut backoff = Backoff::new("test", ReconnectPolicy { jitter: 1.0, ..policy() });
+        for _ in 0..10 {
+            assert!(backoff.next_delay() <= Duration::from_millis(1000));
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//** Downlink commands relay */
+//** Commands from Phoenix `commands` channel are published to devices/<uid>/cmd, */
+//** responses from devices/<uid>/cmd/response are correlated by command id and reported back to Phoenix */
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use log::{info, warn, error};
+use crossbeam::channel;
+use serde_json::{Value, json};
+use uuid::Uuid;
+use websocket::OwnedMessage;
+
+use super::feeder::Command;
+use super::phoenix;
+
+pub const COMMANDS_TOPIC: &str = "commands";
+pub const RESULT_EVENT: &str = "result";
+
+pub fn command_topic(uid: &str) -> String {
+    format!("devices/{}/cmd", uid)
+}
+
+/// Return device UID if the topic is a command response topic: devices/<uid>/cmd/response
+pub fn response_uid(topic: &str) -> Option<&str> {
+    let parts: Vec<&str> = topic.split('/').collect();
+    match parts.as_slice() {
+        ["devices", uid, "cmd", "response"] => Some(uid),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+struct PendingCommand {
+    uid: String,
+    deadline: Instant,
+}
+
+/// Report command result to Phoenix
+fn report(phoenix_sender: &channel::Sender<OwnedMessage>, id: &str, uid: &str, status: &str, response: Value) {
+    let message = phoenix::push_message(COMMANDS_TOPIC, RESULT_EVENT,
+        json!({ "id": id, "uid": uid, "status": status, "response": response }));
+    if let Err(error) = phoenix_sender.send(message) {
+        error!("Commands Relay thread error: {}", error);
+    }
+}
+
+/// A thread which relays commands from Phoenix to devices and responses back.
+/// A command without response during `timeout` (or `timeout` field of the command in seconds) is reported as timed out
+pub fn relay(commands_receiver: channel::Receiver<Command>, publisher_sender: channel::Sender<Command>,
+        phoenix_sender: channel::Sender<OwnedMessage>, timeout: Duration) {
+    use Command::*;
+    let mut pending: BTreeMap<String, PendingCommand> = BTreeMap::new();
+
+    loop {
+        match commands_receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(DeviceCommand(uid, command)) => {
+                let id = match command.get("id") {
+                    Some(Value::String(id)) => id.clone(),
+                    Some(Value::Number(id)) => id.to_string(),
+                    _ => Uuid::new_v4().to_string(),
+                };
+                let command_timeout = command.get("timeout").and_then(|t| t.as_u64())
+                    .map(Duration::from_secs).unwrap_or(timeout);
+
+                let payload = json!({
+                    "id": id,
+                    "command": command.get("command").cloned().unwrap_or(Value::Null),
+                    "params": command.get("params").cloned().unwrap_or(Value::Null),
+                });
+                info!("Send command {} to device {}", &id, &uid);
+                if let Err(error) = publisher_sender.send(Publish(command_topic(&uid), payload.to_string())) {
+                    error!("Commands Relay thread error: {}", error);
+                    report(&phoenix_sender, &id, &uid, "error", json!("cannot publish the command"));
+                    continue;
+                }
+                pending.insert(id, PendingCommand { uid, deadline: Instant::now() + command_timeout });
+            },
+            Ok(CommandResponse(uid, payload)) => {
+                let response = match serde_json::from_str::<Value>(&payload) {
+                    Ok(value) => value,
+                    Err(error) => {
+                        error!("Cannot parse command response from {} due to error: {}", &uid, error);
+                        continue;
+                    }
+                };
+                let id = match response.get("id") {
+                    Some(Value::String(id)) => id.clone(),
+                    Some(Value::Number(id)) => id.to_string(),
+                    _ => {
+                        warn!("Command response from {} without id: {}", &uid, payload);
+                        continue;
+                    }
+                };
+                match pending.get(&id) {
+                    Some(command) if command.uid == uid => {
+                        pending.remove(&id);
+                        let status = response.get("status").and_then(|s| s.as_str()).unwrap_or("ok").to_string();
+                        report(&phoenix_sender, &id, &uid, &status, response);
+                    },
+                    _ => {
+                        warn!("Unexpected response for command {} from device {}", &id, &uid);
+                    }
+                }
+            },
+            Ok(message) => {
+                warn!("Commands Relay thread: unimplemented message: {:?}", message);
+            },
+            Err(channel::RecvTimeoutError::Timeout) => {},
+            Err(channel::RecvTimeoutError::Disconnected) => {
+                return;
+            }
+        }
+
+        // report expired commands
+        let now = Instant::now();
+        let expired: Vec<String> = pending.iter()
+            .filter(|(_, command)| command.deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            if let Some(command) = pending.remove(&id) {
+                warn!("Command {} to device {} timed out", &id, &command.uid);
+                report(&phoenix_sender, &id, &command.uid, "timeout", Value::Null);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_uid() {
+        assert_eq!(response_uid("devices/uid-1/cmd/response"), Some("uid-1"));
+        assert_eq!(response_uid("devices/uid-1/data"), None);
+    }
+
+    #[test]
+    fn test_relay_timeout() {
+        let (commands_sender, commands_receiver) = channel::unbounded();
+        let (publisher_sender, publisher_receiver) = channel::unbounded();
+        let (phoenix_sender, phoenix_receiver) = channel::unbounded();
+
+        let relay_thread = std::thread::spawn(move || {
+            relay(commands_receiver, publisher_sender, phoenix_sender, Duration::from_secs(0));
+        });
+        commands_sender.send(Command::DeviceCommand("uid-1".to_string(), json!({"id": "c1", "command": "reboot"}))).unwrap();
+
+        match publisher_receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Command::Publish(topic, _) => assert_eq!(topic, "devices/uid-1/cmd"),
+            message => panic!("unexpected message {:?}", message),
+        }
+        match phoenix_receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+            OwnedMessage::Text(text) => assert!(text.contains("timeout")),
+            message => panic!("unexpected message {:?}", message),
+        }
+        drop(commands_sender);
+        relay_thread.join().unwrap();
+    }
+}
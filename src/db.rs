@@ -0,0 +1,226 @@
+//** MySQL access layer used by DBStorage thread */
+//** Every operation takes a connection from the pool, the pool checks connection health before giving it out */
+
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, warn, error};
+use mysql::prelude::*;
+use mysql::{Pool, PooledConn, TxOpts, DriverError};
+use mysql::params;
+use chrono::prelude::*;
+
+use super::backoff::{Backoff, ReconnectPolicy};
+use super::feeder::{DeviceMap, UnitMap, DevicesUnitsStorage};
+use super::sink::Record;
+
+/// Attempts of a synchronous operation (load, create) before giving up
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Max time a synchronous operation is retried, writes of DBStorage wait meanwhile
+pub const RETRY_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// Failed write. If the connection is lost after COMMIT is sent, the write could be done, so it is not re-issued
+#[derive(Debug)]
+pub struct WriteError {
+    pub error: mysql::Error,
+    pub committing: bool,
+}
+
+impl From<mysql::Error> for WriteError {
+    fn from(error: mysql::Error) -> Self {
+        WriteError { error, committing: false }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.committing {
+            write!(f, "{} on commit", self.error)
+        } else {
+            write!(f, "{}", self.error)
+        }
+    }
+}
+
+pub fn timestamp() -> String {
+    format_timestamp(Utc::now())
+}
+
+pub fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Errors which could disappear on retry: lost connection, server restart, deadlock, lock wait timeout
+pub fn is_transient(error: &mysql::Error) -> bool {
+    match error {
+        mysql::Error::IoError(_) | mysql::Error::CodecError(_) => true,
+        mysql::Error::DriverError(error) => matches!(error,
+            DriverError::ConnectTimeout | DriverError::CouldNotConnect(_) | DriverError::Timeout | DriverError::PacketOutOfSync),
+        // too many connections, server shutdown, lock wait timeout, deadlock, server has gone away, lost connection
+        mysql::Error::MySqlError(error) => matches!(error.code, 1040 | 1053 | 1205 | 1213 | 2006 | 2013),
+        _ => false,
+    }
+}
+
+/// Create connection pool, retry with backoff until DB is available
+pub fn connect(db_host: &str, policy: ReconnectPolicy) -> Pool {
+    let mut backoff = Backoff::new("mysql", policy);
+    loop {
+        match Pool::new(db_host) {
+            Ok(mut pool) => {
+                pool.check_health(true);
+                backoff.reset();
+                return pool;
+            },
+            Err(error) => {
+                error!("Cannot connect to DB: {}", error);
+                backoff.wait();
+            }
+        }
+    }
+}
+
+/// Run the operation on a pooled connection, retry it with backoff in case of transient errors
+/// up to MAX_ATTEMPTS times within RETRY_TIME_LIMIT
+pub fn with_retry<T, F>(pool: &Pool, policy: ReconnectPolicy, mut operation: F) -> mysql::Result<T>
+where F: FnMut(&mut PooledConn) -> mysql::Result<T> {
+    // retries of queries are counted apart from reconnections of the pool
+    let mut backoff = Backoff::new("mysql_query", policy);
+    let started = Instant::now();
+    loop {
+        match pool.get_conn().and_then(|mut conn| operation(&mut conn)) {
+            Ok(result) => {
+                backoff.reset();
+                return Ok(result);
+            },
+            Err(error) if is_transient(&error) && backoff.attempt() + 1 < MAX_ATTEMPTS => {
+                let delay = backoff.next_delay();
+                if started.elapsed() + delay > RETRY_TIME_LIMIT {
+                    return Err(error);
+                }
+                warn!("DB operation failed due to error: {}, retry in {:?}", error, delay);
+                thread::sleep(delay);
+            },
+            Err(error) => {
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// Load all devices, inactive and deleted devices are loaded as None
+pub fn load_devices(conn: &mut PooledConn) -> mysql::Result<DeviceMap> {
+    conn.query_map("SELECT id, uid, COALESCE(active, FALSE), deleted_at IS NOT NULL FROM devices;",
+        |(id, uid, active, deleted): (usize, String, bool, bool)| {
+            if active && !deleted {
+                (uid, Some(id))
+            } else {
+                (uid, None)
+            }
+    }).map(|devices| devices.into_iter().collect())
+}
+
+pub fn load_units(conn: &mut PooledConn) -> mysql::Result<UnitMap> {
+    conn.query_map("SELECT id, name FROM units;", |(id, name): (usize, String)| (name, id))
+        .map(|units| units.into_iter().collect())
+}
+
+pub fn load_devices_units(conn: &mut PooledConn) -> mysql::Result<DevicesUnitsStorage> {
+    let mut devices_units = DevicesUnitsStorage::new();
+    conn.query_map("SELECT unit_id, device_id FROM devices_units;", |(unit_id, device_id): (usize, usize)| {
+        devices_units.add(unit_id, device_id, true);
+    })?;
+    Ok(devices_units)
+}
+
+/// Create a unit or return id of the existing unit with the same name (relies on the unique index on units.name).
+/// None if the unit has no id after the insert
+pub fn create_unit(conn: &mut PooledConn, name: &str) -> mysql::Result<Option<usize>> {
+    let utc_timestamp = timestamp();
+    // on conflict LAST_INSERT_ID(id) makes LAST_INSERT_ID() return id of the existing record
+    conn.exec_drop("INSERT INTO units (name, inserted_at, updated_at) VALUES (:name, :inserted_at, :updated_at)
+                ON DUPLICATE KEY UPDATE id = LAST_INSERT_ID(id);",
+        params! { "name" => name, "inserted_at" => &utc_timestamp, "updated_at" => &utc_timestamp })?;
+
+    // Get ID of the record. In case of PostgreSQL could be replaced with INSERT ... ON CONFLICT ... RETURNING id
+    match conn.query_first("SELECT LAST_INSERT_ID();")? {
+        Some(id) if id > 0 => Ok(Some(id)),
+        // LAST_INSERT_ID() is 0 if the row was not touched, look the unit up by name
+        _ => conn.exec_first("SELECT id FROM units WHERE name = :name;", params! { "name" => name }),
+    }
+}
+
+/// Insert all records of a message in one transaction, inserted_at is the measurement time
+pub fn store_records(conn: &mut PooledConn, device_id: usize, records: &[Record]) -> Result<(), WriteError> {
+    let utc_timestamp = timestamp();
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+    for record in records {
+        tx.exec_drop("INSERT INTO records (device_id, unit_id, value, inserted_at, updated_at) VALUES (:device_id, :unit_id, :value, :inserted_at, :updated_at)",
+            params! { "device_id" => device_id, "unit_id" => record.unit_id, "value" => record.value.to_string(),
+                "inserted_at" => format_timestamp(record.timestamp), "updated_at" => &utc_timestamp })?;
+    }
+    tx.commit().map_err(|error| WriteError { error, committing: true })
+}
+
+/// Link a device to a unit, an existing link is kept (relies on the unique index on devices_units)
+pub fn link_device_to_unit(conn: &mut PooledConn, device_id: usize, unit_id: usize) -> mysql::Result<()> {
+    info!("Create a record in devices_units table: (device_id: {}, unit_id: {}", device_id, unit_id);
+    let utc_timestamp = timestamp();
+    conn.exec_drop("INSERT INTO devices_units (device_id, unit_id, inserted_at, updated_at)
+                VALUES (:device_id, :unit_id, :inserted_at, :updated_at)
+                ON DUPLICATE KEY UPDATE updated_at = VALUES(updated_at);",
+        params! { "device_id" => device_id, "unit_id" => unit_id, "inserted_at" => &utc_timestamp, "updated_at" => &utc_timestamp })
+}
+
+/// Set last_seen_at of device units: (device_id, unit_id, time)
+pub fn update_last_seen(conn: &mut PooledConn, seen: &[(usize, usize, DateTime<Utc>)]) -> mysql::Result<()> {
+    conn.exec_batch("UPDATE devices_units SET last_seen_at = GREATEST(COALESCE(last_seen_at, :last_seen_at), :last_seen_at)
+                WHERE device_id = :device_id AND unit_id = :unit_id;",
+        seen.iter().map(|(device_id, unit_id, time)| params! { "device_id" => device_id, "unit_id" => unit_id, "last_seen_at" => format_timestamp(*time) }))
+}
+
+/// Remove duplicated devices_units links, the oldest link is kept. Return amount of removed rows
+pub fn remove_duplicate_links(conn: &mut PooledConn) -> mysql::Result<u64> {
+    conn.query_drop("DELETE duplicate FROM devices_units duplicate
+                JOIN devices_units original ON duplicate.device_id = original.device_id
+                    AND duplicate.unit_id = original.unit_id AND duplicate.id > original.id;")?;
+    Ok(conn.affected_rows())
+}
+
+/// Merge units with the same name into the oldest one, records and links are moved to it.
+/// Return amount of removed units
+pub fn merge_duplicate_units(conn: &mut PooledConn) -> mysql::Result<u64> {
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+    // a device linked to several units of a name keeps the link to the oldest unit only,
+    // moving the other links would break the unique index on (device_id, unit_id)
+    tx.query_drop("DELETE duplicate FROM devices_units duplicate
+                JOIN units duplicate_unit ON duplicate.unit_id = duplicate_unit.id
+                JOIN units kept_unit ON kept_unit.name = duplicate_unit.name AND kept_unit.id < duplicate_unit.id
+                JOIN devices_units kept ON kept.device_id = duplicate.device_id AND kept.unit_id = kept_unit.id;")?;
+    for table in &["records", "devices_units"] {
+        tx.query_drop(format!("UPDATE {} target
+                JOIN units duplicate ON target.unit_id = duplicate.id
+                JOIN (SELECT name, MIN(id) AS id FROM units GROUP BY name) original ON duplicate.name = original.name
+                SET target.unit_id = original.id
+                WHERE duplicate.id <> original.id;", table))?;
+    }
+    tx.query_drop("DELETE duplicate FROM units duplicate
+                JOIN units original ON duplicate.name = original.name AND duplicate.id > original.id;")?;
+    let removed = tx.affected_rows();
+    tx.commit()?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_transient_errors() {
+        let lost = mysql::Error::IoError(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+        assert!(is_transient(&lost));
+        assert!(is_transient(&mysql::Error::DriverError(DriverError::ConnectTimeout)));
+        assert!(!is_transient(&mysql::Error::DriverError(DriverError::MixedParams)));
+    }
+}
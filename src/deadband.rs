@@ -0,0 +1,164 @@
+//** Change-only recording */
+//** Per unit bands (overridden per device): a value is stored only if it differs from the last stored one by more than */
+//** the threshold or the max interval has elapsed. Suppressed values still update last_seen_at of the device unit */
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::Mutex;
+use chrono::prelude::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::metrics;
+use super::sink::Record;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Band {
+    /// Min absolute change of numbers, 0 stores every change; other values are stored when they change
+    pub threshold: f64,
+    /// Seconds after which a value is stored even if it is unchanged, 0 to never force
+    pub max_interval: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DeadbandConfig {
+    /// Bands by unit name
+    pub units: BTreeMap<String, Band>,
+    /// Bands by device UID and unit name, they replace the unit bands
+    pub devices: BTreeMap<String, BTreeMap<String, Band>>,
+    /// Seconds between writes of last_seen_at
+    pub last_seen_interval: u64,
+}
+
+impl Default for DeadbandConfig {
+    fn default() -> Self {
+        DeadbandConfig {
+            units: BTreeMap::new(),
+            devices: BTreeMap::new(),
+            last_seen_interval: 60,
+        }
+    }
+}
+
+impl DeadbandConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.units.is_empty() || !self.devices.is_empty()
+    }
+}
+
+impl Band {
+    /// True if the value should be stored after the last stored value and its time
+    pub fn accepts(&self, value: &Value, timestamp: DateTime<Utc>, last: Option<&(Value, DateTime<Utc>)>) -> bool {
+        let (last_value, stored_at) = match last {
+            Some(last) => last,
+            None => return true,
+        };
+        if self.max_interval > 0 && timestamp.signed_duration_since(*stored_at).num_seconds() >= self.max_interval as i64 {
+            return true;
+        }
+        match (value.as_f64(), last_value.as_f64()) {
+            (Some(number), Some(last_number)) => (number - last_number).abs() > self.threshold,
+            _ => value != last_value,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Last stored values by device id and unit id
+    stored: HashMap<(usize, usize), (Value, DateTime<Utc>)>,
+    /// Times the device units were seen since the last write of last_seen_at
+    seen: HashMap<(usize, usize), DateTime<Utc>>,
+}
+
+/// Bands with last stored values of devices, shared by processing threads
+pub struct Deadband {
+    config: DeadbandConfig,
+    state: Mutex<State>,
+}
+
+impl Deadband {
+    pub fn new(config: DeadbandConfig) -> Self {
+        Deadband { config, state: Mutex::new(State::default()) }
+    }
+
+    fn band(&self, uid: &str, unit: &str) -> Option<&Band> {
+        self.config.devices.get(uid).and_then(|bands| bands.get(unit)).or_else(|| self.config.units.get(unit))
+    }
+
+    /// Records to store, records of units without a band are kept
+    pub fn filter(&self, records: Vec<Record>) -> Vec<Record> {
+        if !self.config.is_enabled() {
+            return records;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        records.into_iter().filter(|record| {
+            let band = match self.band(&record.uid, &record.unit) {
+                Some(band) => band,
+                None => return true,
+            };
+            let key = (record.device_id, record.unit_id);
+            let seen = state.seen.entry(key).or_insert(record.timestamp);
+            *seen = (*seen).max(record.timestamp);
+
+            let last = state.stored.get(&key);
+            // late values are stored as they are, the band follows the newest value
+            if matches!(last, Some((_, stored_at)) if record.timestamp < *stored_at) {
+                return true;
+            }
+            if band.accepts(&record.value, record.timestamp, last) {
+                state.stored.insert(key, (record.value.clone(), record.timestamp));
+                true
+            } else {
+                metrics::increment("suppressed_values_total");
+                false
+            }
+        }).collect()
+    }
+
+    /// Device id, unit id and time of device units seen since the last call
+    pub fn take_seen(&self) -> Vec<(usize, usize, DateTime<Utc>)> {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        mem::take(&mut state.seen).into_iter().map(|((device_id, unit_id), time)| (device_id, unit_id, time)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use serde_json::json;
+
+    #[test]
+    fn test_band() {
+        let now = Utc::now();
+        let band = Band { threshold: 0.5, max_interval: 60 };
+        let last = (json!(20.0), now);
+        assert!(band.accepts(&json!(20.0), now, None));
+        assert!(!band.accepts(&json!(20.4), now + Duration::seconds(10), Some(&last)));
+        assert!(band.accepts(&json!(20.6), now + Duration::seconds(10), Some(&last)));
+        assert!(band.accepts(&json!(20.0), now + Duration::seconds(60), Some(&last)));
+
+        let any_change = Band::default();
+        assert!(!any_change.accepts(&json!("on"), now, Some(&(json!("on"), now))));
+        assert!(any_change.accepts(&json!("off"), now, Some(&(json!("on"), now))));
+    }
+
+    #[test]
+    fn test_suppressed_records_are_seen() {
+        let mut config = DeadbandConfig::default();
+        config.units.insert("temperature".to_string(), Band { threshold: 1.0, max_interval: 0 });
+        let deadband = Deadband::new(config);
+        let now = Utc::now();
+        let record = |unit: &str, unit_id: usize, value: Value, seconds: i64| Record { device_id: 1, uid: "sensor".to_string(),
+            unit_id, unit: unit.to_string(), value, timestamp: now + Duration::seconds(seconds) };
+
+        assert_eq!(deadband.filter(vec![record("temperature", 1, json!(20), 0), record("humidity", 2, json!(40), 0)]).len(), 2);
+        assert!(deadband.filter(vec![record("temperature", 1, json!(20.5), 1)]).is_empty());
+        assert_eq!(deadband.take_seen(), vec![(1, 1, now + Duration::seconds(1))]);
+        assert!(deadband.take_seen().is_empty());
+        assert_eq!(deadband.filter(vec![record("temperature", 1, json!(21.5), 2)]).len(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+//** Deduplication of redelivered messages */
+//** A message is identified by the device UID and its msg_id (or the hash of the payload). A message seen */
+//** within the window is dropped. A message is pending until it is stored, a message which cannot be stored is forgotten, */
+//** so its redelivery is stored. Seen messages are kept in memory and optionally in a file, one "<time> <key>" per line */
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use log::{info, error};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use super::metrics;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DedupConfig {
+    /// Seconds a message is remembered
+    pub window: u64,
+    /// Identify messages without msg_id by the payload hash; a device sending the same reading twice
+    /// within the window loses the second one
+    pub by_payload: bool,
+    /// File keeping seen messages between restarts
+    pub file: Option<String>,
+    /// Max amount of remembered messages, the oldest are forgotten
+    pub max_entries: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            window: 600,
+            by_payload: false,
+            file: None,
+            max_entries: 100_000,
+        }
+    }
+}
+
+struct Seen {
+    keys: HashMap<String, i64>,
+    order: VecDeque<(i64, String)>,
+    /// Messages which are not stored yet, they are not written to the file
+    pending: HashSet<String>,
+    /// Lines appended to the file since it was written
+    appended: usize,
+}
+
+impl Seen {
+    fn expire(&mut self, now: i64, window: i64, max_entries: usize) {
+        while let Some((time, key)) = self.order.front() {
+            if *time > now - window && self.order.len() <= max_entries {
+                break;
+            }
+            // a key seen again has a newer time in the map
+            if self.keys.get(key) == Some(time) {
+                self.keys.remove(key);
+                self.pending.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+
+    fn insert(&mut self, time: i64, key: String) {
+        self.keys.insert(key.clone(), time);
+        self.order.push_back((time, key));
+    }
+}
+
+pub struct Deduplicator {
+    config: DedupConfig,
+    seen: Mutex<Seen>,
+}
+
+impl Deduplicator {
+    pub fn new(config: DedupConfig) -> Self {
+        let mut seen = Seen { keys: HashMap::new(), order: VecDeque::new(), pending: HashSet::new(), appended: 0 };
+        if let Some(content) = config.file.as_ref().and_then(|path| fs::read_to_string(path).ok()) {
+            for line in content.lines() {
+                let mut parts = line.splitn(2, ' ');
+                if let (Some(Ok(time)), Some(key)) = (parts.next().map(str::parse), parts.next()) {
+                    seen.insert(time, key.to_string());
+                }
+            }
+            seen.expire(Utc::now().timestamp(), config.window as i64, config.max_entries);
+            info!("Loaded {} seen messages", seen.keys.len());
+        }
+        let deduplicator = Deduplicator { config, seen: Mutex::new(seen) };
+        deduplicator.compact(&mut deduplicator.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        deduplicator
+    }
+
+    /// Key of a message, None if the message cannot be identified
+    pub fn key(&self, uid: &str, message_id: &Value, payload: &[u8]) -> Option<String> {
+        match message_id {
+            Value::Null if self.config.by_payload => Some(format!("{}:sha256:{}", uid, hex::encode(Sha256::digest(payload)))),
+            Value::Null => None,
+            id => Some(format!("{}:id:{}", uid, id)),
+        }
+    }
+
+    /// Check the message and remember it as pending, true if it is seen within the window
+    pub fn is_duplicate(&self, key: &str) -> bool {
+        let now = Utc::now().timestamp();
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        seen.expire(now, self.config.window as i64, self.config.max_entries);
+        if seen.keys.contains_key(key) {
+            metrics::increment("duplicate_messages_total");
+            return true;
+        }
+        seen.insert(now, key.to_string());
+        seen.pending.insert(key.to_string());
+        false
+    }
+
+    /// The message is stored, its redeliveries are duplicates
+    pub fn confirm(&self, key: &str) {
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !seen.pending.remove(key) {
+            return;
+        }
+        if let (Some(path), Some(time)) = (&self.config.file, seen.keys.get(key)) {
+            let appended = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{} {}", time, key));
+            if let Err(error) = appended {
+                error!("Cannot write seen messages to {}: {}", path, error);
+            }
+        }
+        seen.appended += 1;
+        if seen.appended > seen.order.len().max(1000) {
+            self.compact(&mut seen);
+        }
+    }
+
+    /// The message is not stored, its redelivery is stored
+    pub fn forget(&self, key: &str) {
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if seen.pending.remove(key) {
+            seen.keys.remove(key);
+        }
+    }
+
+    /// Rewrite the file with the remembered stored messages only
+    fn compact(&self, seen: &mut Seen) {
+        if let Some(path) = &self.config.file {
+            let mut content = String::new();
+            for (time, key) in &seen.order {
+                if seen.keys.get(key) == Some(time) && !seen.pending.contains(key) {
+                    content.push_str(&format!("{} {}\n", time, key));
+                }
+            }
+            let temporary = format!("{}.tmp", path);
+            if let Err(error) = fs::write(&temporary, content).and_then(|_| fs::rename(&temporary, path)) {
+                error!("Cannot write seen messages to {}: {}", path, error);
+            }
+        }
+        seen.appended = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use serde_json::json;
+
+    #[test]
+    fn test_duplicates_survive_restart() {
+        let path = env::temp_dir().join(format!("ffeeder-dedup-{}.txt", std::process::id())).to_string_lossy().to_string();
+        let config = DedupConfig { file: Some(path.clone()), ..DedupConfig::default() };
+        let deduplicator = Deduplicator::new(config.clone());
+        let key = deduplicator.key("sensor", &json!(42), b"{}").unwrap();
+        assert!(!deduplicator.is_duplicate(&key));
+        // a redelivery while the message is being stored is a duplicate as well
+        assert!(deduplicator.is_duplicate(&key));
+        deduplicator.confirm(&key);
+        assert!(deduplicator.is_duplicate(&key));
+        assert_eq!(deduplicator.key("sensor", &Value::Null, b"{}"), None);
+        let pending = deduplicator.key("sensor", &json!(43), b"{}").unwrap();
+        assert!(!deduplicator.is_duplicate(&pending));
+
+        // pending messages are not kept between restarts
+        let restarted = Deduplicator::new(config);
+        assert!(restarted.is_duplicate(&key));
+        assert!(!restarted.is_duplicate(&pending));
+        assert!(!restarted.is_duplicate(&restarted.key("other", &json!(42), b"{}").unwrap()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_redelivery_after_failed_store_is_stored() {
+        let deduplicator = Deduplicator::new(DedupConfig::default());
+        let key = deduplicator.key("sensor", &json!(7), b"{}").unwrap();
+        assert!(!deduplicator.is_duplicate(&key));
+        deduplicator.forget(&key);
+        assert!(!deduplicator.is_duplicate(&key));
+        deduplicator.confirm(&key);
+        // a stored message is not forgotten by a late failure of another sink
+        deduplicator.forget(&key);
+        assert!(deduplicator.is_duplicate(&key));
+    }
+
+    #[test]
+    fn test_expiration_by_window_and_size() {
+        let mut seen = Seen { keys: HashMap::new(), order: VecDeque::new(), pending: HashSet::new(), appended: 0 };
+        seen.insert(100, "a".to_string());
+        seen.insert(150, "b".to_string());
+        seen.insert(160, "c".to_string());
+        seen.expire(200, 60, 10);
+        assert_eq!(seen.order.len(), 2);
+        seen.expire(200, 60, 1);
+        assert!(seen.keys.contains_key("c") && !seen.keys.contains_key("b"));
+    }
+}
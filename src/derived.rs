@@ -0,0 +1,356 @@
+//** Derived units */
+//** Units computed from other units of the same message, e.g. "power": "voltage * current". Expressions are parsed */
+//** at start by a small parser: numbers, units, + - * / % ^, parentheses and a few math functions. Units with other */
+//** characters are written in braces: {env.air temp}. A derived value is stored if all its units are numbers */
+
+use std::collections::{BTreeMap, HashMap};
+use chrono::prelude::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::sink::Record;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DerivedConfig {
+    /// Expressions by derived unit name, computed for every device sending their units
+    pub units: BTreeMap<String, String>,
+    /// Expressions by device UID and derived unit name, they replace the unit expressions
+    pub devices: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Abs,
+    Sqrt,
+    Ln,
+    Log10,
+    Exp,
+    Round,
+    Min,
+    Max,
+    Pow,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<(Self, usize)> {
+        match name {
+            "abs" => Some((Function::Abs, 1)),
+            "sqrt" => Some((Function::Sqrt, 1)),
+            "ln" => Some((Function::Ln, 1)),
+            "log10" => Some((Function::Log10, 1)),
+            "exp" => Some((Function::Exp, 1)),
+            "round" => Some((Function::Round, 1)),
+            "min" => Some((Function::Min, 2)),
+            "max" => Some((Function::Max, 2)),
+            "pow" => Some((Function::Pow, 2)),
+            _ => None,
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        match self {
+            Function::Abs => args[0].abs(),
+            Function::Sqrt => args[0].sqrt(),
+            Function::Ln => args[0].ln(),
+            Function::Log10 => args[0].log10(),
+            Function::Exp => args[0].exp(),
+            Function::Round => args[0].round(),
+            Function::Min => args[0].min(args[1]),
+            Function::Max => args[0].max(args[1]),
+            Function::Pow => args[0].powf(args[1]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(f64),
+    Unit(String),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    /// Unit in braces, it is never a function
+    Unit(String),
+    Symbol(char),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+
+    while position < chars.len() {
+        let c = chars[position];
+        let start = position;
+        if c.is_whitespace() {
+            position += 1;
+        } else if c.is_ascii_digit() || (c == '.' && matches!(chars.get(position + 1), Some(next) if next.is_ascii_digit())) {
+            while position < chars.len() && (chars[position].is_ascii_digit() || chars[position] == '.') {
+                position += 1;
+            }
+            // exponent: 1e-3
+            if position < chars.len() && (chars[position] == 'e' || chars[position] == 'E') {
+                let mut end = position + 1;
+                if end < chars.len() && (chars[end] == '+' || chars[end] == '-') {
+                    end += 1;
+                }
+                if end < chars.len() && chars[end].is_ascii_digit() {
+                    position = end;
+                    while position < chars.len() && chars[position].is_ascii_digit() {
+                        position += 1;
+                    }
+                }
+            }
+            let number: String = chars[start..position].iter().collect();
+            tokens.push(Token::Number(number.parse().map_err(|_| format!("invalid number {}", number))?));
+        } else if c.is_alphabetic() || c == '_' {
+            while position < chars.len() && (chars[position].is_alphanumeric() || chars[position] == '_' || chars[position] == '.') {
+                position += 1;
+            }
+            tokens.push(Token::Name(chars[start..position].iter().collect()));
+        } else if c == '{' {
+            let end = chars[position..].iter().position(|c| *c == '}').map(|offset| position + offset)
+                .ok_or_else(|| "unclosed {".to_string())?;
+            let unit: String = chars[position + 1..end].iter().collect();
+            if unit.trim().is_empty() {
+                return Err("empty unit in braces".to_string());
+            }
+            tokens.push(Token::Unit(unit.trim().to_string()));
+            position = end + 1;
+        } else if "+-*/%^(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            position += 1;
+        } else {
+            return Err(format!("unexpected {}", c));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        if self.eat(symbol) { Ok(()) } else { Err(format!("expected {}", symbol)) }
+    }
+
+    /// sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut left = self.product()?;
+        while let Some(Token::Symbol(operator @ ('+' | '-'))) = self.peek().cloned() {
+            self.position += 1;
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    /// product := unary (('*' | '/' | '%') unary)*
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while let Some(Token::Symbol(operator @ ('*' | '/' | '%'))) = self.peek().cloned() {
+            self.position += 1;
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    /// unary := '-' unary | power, so -x^2 is -(x^2)
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    /// power := primary ('^' unary)?, right associative
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.primary()?;
+        if self.eat('^') {
+            return Ok(Expr::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    /// primary := number | unit | function '(' sum (',' sum)* ')' | '(' sum ')'
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Unit(unit)) => Ok(Expr::Unit(unit)),
+            Some(Token::Name(name)) if self.eat('(') => {
+                let (function, arity) = Function::parse(&name).ok_or_else(|| format!("unknown function {}", name))?;
+                let mut args = vec![self.sum()?];
+                while self.eat(',') {
+                    args.push(self.sum()?);
+                }
+                self.expect(')')?;
+                if args.len() != arity {
+                    return Err(format!("{} takes {} arguments", name, arity));
+                }
+                Ok(Expr::Call(function, args))
+            },
+            Some(Token::Name(name)) => Ok(Expr::Unit(name)),
+            Some(Token::Symbol('(')) => {
+                let inner = self.sum()?;
+                self.expect(')')?;
+                Ok(inner)
+            },
+            Some(Token::Symbol(symbol)) => Err(format!("unexpected {}", symbol)),
+            None => Err("unexpected end".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    fn parse(source: &str) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(source)?, position: 0 };
+        let expr = parser.sum()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    /// Value by units, None if a unit is missing
+    fn evaluate(&self, units: &HashMap<&str, f64>) -> Option<f64> {
+        match self {
+            Expr::Number(number) => Some(*number),
+            Expr::Unit(unit) => units.get(unit.as_str()).copied(),
+            Expr::Neg(inner) => inner.evaluate(units).map(|value| -value),
+            Expr::Binary(operator, left, right) => {
+                let (left, right) = (left.evaluate(units)?, right.evaluate(units)?);
+                Some(match operator {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    '/' => left / right,
+                    '%' => left % right,
+                    _ => left.powf(right),
+                })
+            },
+            Expr::Call(function, args) => {
+                let args = args.iter().map(|arg| arg.evaluate(units)).collect::<Option<Vec<f64>>>()?;
+                Some(function.apply(&args))
+            },
+        }
+    }
+}
+
+/// Parsed expressions of derived units
+pub struct Derived {
+    units: BTreeMap<String, Expr>,
+    devices: BTreeMap<String, BTreeMap<String, Expr>>,
+}
+
+fn parse_all(expressions: &BTreeMap<String, String>) -> Result<BTreeMap<String, Expr>, String> {
+    expressions.iter()
+        .map(|(unit, source)| Expr::parse(source).map(|expr| (unit.clone(), expr)).map_err(|error| format!("{}: {}", unit, error)))
+        .collect()
+}
+
+impl Derived {
+    pub fn new(config: &DerivedConfig) -> Result<Self, String> {
+        let devices = config.devices.iter()
+            .map(|(uid, expressions)| parse_all(expressions).map(|expressions| (uid.clone(), expressions)))
+            .collect::<Result<_, String>>()?;
+        Ok(Derived { units: parse_all(&config.units)?, devices })
+    }
+
+    /// Derived units, values and times of records of a message. A unit sent by the device itself is not derived,
+    /// values which are not finite numbers (e.g. division by zero) are skipped
+    pub fn evaluate(&self, uid: &str, records: &[Record]) -> Vec<(String, Value, DateTime<Utc>)> {
+        if records.is_empty() || (self.units.is_empty() && !self.devices.contains_key(uid)) {
+            return Vec::new();
+        }
+        let units: HashMap<&str, f64> = records.iter()
+            .filter_map(|record| record.value.as_f64().map(|value| (record.unit.as_str(), value)))
+            .collect();
+        let timestamp = records.iter().map(|record| record.timestamp).max().unwrap_or_else(Utc::now);
+
+        let mut expressions: BTreeMap<&String, &Expr> = self.units.iter().collect();
+        if let Some(device_expressions) = self.devices.get(uid) {
+            expressions.extend(device_expressions.iter());
+        }
+        expressions.into_iter()
+            .filter(|(unit, _)| !records.iter().any(|record| &&record.unit == unit))
+            .filter_map(|(unit, expr)| expr.evaluate(&units)
+                .and_then(serde_json::Number::from_f64)
+                .map(|value| (unit.clone(), Value::Number(value), timestamp)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_and_evaluate() {
+        let units: HashMap<&str, f64> = vec![("x", 3.0), ("env.air temp", 20.0)].into_iter().collect();
+        let value = |source: &str| Expr::parse(source).unwrap().evaluate(&units);
+        assert_eq!(value("1 + 2 * x"), Some(7.0));
+        assert_eq!(value("(1 + 2) * x"), Some(9.0));
+        assert_eq!(value("-x^2"), Some(-9.0));
+        assert_eq!(value("2^3^2"), Some(512.0));
+        assert_eq!(value("2^-1 + 1e-1"), Some(0.6));
+        assert_eq!(value("max({env.air temp}, x) % 7 - abs(-1)"), Some(5.0));
+        assert_eq!(value("y + 1"), None);
+        assert!(Expr::parse("1 +").is_err());
+        assert!(Expr::parse("sqrt(1, 2)").is_err());
+        assert!(Expr::parse("system(1)").is_err());
+        assert!(Expr::parse("x; 1").is_err());
+    }
+
+    #[test]
+    fn test_derived_records() {
+        let mut config = DerivedConfig::default();
+        config.units.insert("power".to_string(), "voltage * current".to_string());
+        config.units.insert("inverse".to_string(), "1 / current".to_string());
+        let mut device_expressions = BTreeMap::new();
+        device_expressions.insert("power".to_string(), "voltage * current * 0.5".to_string());
+        config.devices.insert("inverter".to_string(), device_expressions);
+        let derived = Derived::new(&config).unwrap();
+
+        let now = Utc::now();
+        let record = |unit: &str, value: Value| Record { device_id: 1, uid: "meter".to_string(), unit_id: 1,
+            unit: unit.to_string(), value, timestamp: now };
+        let records = vec![record("voltage", json!(230)), record("current", json!(0))];
+        // 1 / 0 is not stored
+        assert_eq!(derived.evaluate("meter", &records), vec![("power".to_string(), json!(0.0), now)]);
+        let records = vec![record("voltage", json!(200)), record("current", json!(2))];
+        assert_eq!(derived.evaluate("inverter", &records)[1], ("power".to_string(), json!(200.0), now));
+        assert!(derived.evaluate("meter", &[record("voltage", json!("230"))]).is_empty());
+
+        config.units.insert("broken".to_string(), "voltage *".to_string());
+        assert!(Derived::new(&config).is_err());
+    }
+}
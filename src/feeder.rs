@@ -1,49 +1,111 @@
 use std::{
   process,
   thread,
-  time::Duration,
+  time::{Duration, Instant},
 };
 use std::sync::mpsc::Receiver;
-use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use paho_mqtt as mqtt;
 use log::{info, trace, warn, error};
 use crossbeam::channel;
-use serde_json::{Result, Value, Map};
-use mysql::prelude::*;
+use serde_json::{Result, Value, Map, json};
 use mysql::Pool;
-use mysql::params;
-use chrono::prelude::*;
+use chrono::{DateTime, Utc};
 
 use super::matrix_storage::*;
+use super::commands;
+use super::backoff::{Backoff, ReconnectPolicy};
+use super::config::Config;
+use super::db;
+use super::resync::{self, Diff};
+use super::sink::{self, Event, Record, Route, Target};
+use super::payload::{self, Decoder, Format, Measurement};
+use super::sparkplug::{self, Tracker, Update};
+use super::gateway;
+use super::validation::Validator;
+use super::dedup::Deduplicator;
+use super::deadband::Deadband;
+use super::derived::Derived;
+use super::metrics;
 
 #[derive(Debug)]
 pub enum Command {
-    Add(String, String),
-    Store(usize, BTreeMap<usize, String>),
+    Add(String, Vec<u8>, Format), // device UID, raw payload and its encoding
+    Measurements(String, Vec<Measurement>), // device UID and measurements decoded by the subscriber (Sparkplug B)
+    Store(usize, Vec<Record>, Ack), // device id and resolved records of a message
     Load(channel::Sender<DeviceMap>),                   // load devices from DB
     UpdateDeviceList(DeviceMap),
     Activate(usize, String), // id and uid
+    Deactivate(usize, String), // id and uid
     ActivateUnit(usize, String), // id and name
     LoadUnits(channel::Sender<UnitMap>),
-    CreateUnit(String, channel::Sender<Option<usize>>),
+    CreateUnit(String, channel::Sender<UnitResult>), // create a unit or get id of the existing one
     LoadDevicesUnits(channel::Sender<DevicesUnitsStorage>), //load table with lnk between devices and units 
-    GetUnit(String, channel::Sender<Option<usize>>), // get unit id by name, or create a new record in DB in case of none
-    CheckDeviceUnit(usize, usize, channel::Sender<bool>), //device_id, unit_id; check if a device has measurements by specific unit type
-    LinkDeviceToUnit(usize, usize), //device_id, unit_id  
+    GetUnit(String, channel::Sender<UnitResult>), // get unit id by name, or create a new record in DB in case of none
+    LinkDeviceToUnit(usize, usize), //device_id, unit_id; link a device to a unit if it's not linked yet
+    Seen(Vec<(usize, usize, DateTime<Utc>)>), // device_id, unit_id and time; update last_seen_at of device units
+    Publish(String, String), // topic and payload, published by the Publisher thread
+    Republish(String, String, i32, bool), // topic, payload, QoS and retain flag of a republished record
+    DeviceCommand(String, Value), // device UID and command from Phoenix (id, command, params, timeout)
+    CommandResponse(String, String), // device UID and response payload
+    Live(usize, Vec<(usize, String, String)>), // device_id and records (unit_id, unit name, value) to push to Phoenix
+    Records(Vec<Record>), // resolved measurements for output sinks
+    Event(Event), // lifecycle event for output sinks
+    Resync, // compare caches with DB and apply the difference
     Disconnect,
 }
 
+/// Payload key which carries the message id, the id is sent back in acknowledgments
+pub const MESSAGE_ID_KEY: &str = "msg_id";
+
+/// Device and message to acknowledge after the measurements are stored
+#[derive(Debug, Clone)]
+pub struct Ack {
+    pub uid: String,
+    pub message_id: Value, // Null if the payload has no message id
+    pub dedup_key: Option<String>, // key of the message pending in the deduplicator
+}
+
+pub fn ack_topic(uid: &str) -> String {
+    format!("devices/{}/ack", uid)
+}
+
+pub fn error_topic(uid: &str) -> String {
+    format!("devices/{}/error", uid)
+}
+
+/// Send a message to the Publisher thread, if acknowledgments are enabled
+fn report(publisher_sender: &Option<channel::Sender<Command>>, topic: String, payload: Value) {
+    if let Some(sender) = publisher_sender {
+        if let Err(error) = sender.send(Command::Publish(topic, payload.to_string())) {
+            error!("Cannot send message to Publisher thread: {}", error);
+        }
+    }
+}
+
+/// Report a rejected message or value to the device and raise an alert event for sinks
+fn reject(publisher_sender: &Option<channel::Sender<Command>>, routes: &[Route], uid: &str, payload: Value) {
+    sink::notify(routes, Event::new("alert", Some(uid.to_string()), payload.clone()));
+    report(publisher_sender, error_topic(uid), payload);
+}
 
 pub type DeviceMap = BTreeMap<String, Option<usize>>;  //device UID and id (from DB)
 pub type UnitMap = BTreeMap<String, usize>; // unit name and id (from DB)
 pub type DevicesUnitsStorage = MatrixStorage<bool>; // unit_id: array of device_ids
+/// Unit id or the reason why the unit cannot be found or created
+pub type UnitResult = std::result::Result<usize, String>;
 
 
 
-pub fn mqtt_connect(mqtt_host: &str, client_id: &str, subscriptions: &[&str], qos: &[i32]) -> (mqtt::Client, Receiver<Option<mqtt::Message>>) {
+/// Seconds the broker keeps the session of a disconnected MQTT v5 subscriber
+const SESSION_EXPIRY_INTERVAL: i32 = 24 * 3600;
+
+pub fn mqtt_connect(mqtt_host: &str, client_id: &str, subscriptions: &[&str], qos: &[i32], mqtt_version: u32, policy: ReconnectPolicy) -> (mqtt::Client, Receiver<Option<mqtt::Message>>) {
     let create_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(mqtt_host)
         .client_id(client_id)
+        .mqtt_version(mqtt_version)
         .finalize();
 
     let mut mqtt_client = mqtt::Client::new(create_opts).unwrap_or_else(|e| {
@@ -51,47 +113,94 @@ pub fn mqtt_connect(mqtt_host: &str, client_id: &str, subscriptions: &[&str], qo
         process::exit(1);
     });
 
-    let conn_opts = mqtt::ConnectOptionsBuilder::new()
-        .keep_alive_interval(Duration::from_secs(20))
-        .clean_session(false)
-        .finalize();
+    let mut conn_builder = mqtt::ConnectOptionsBuilder::new();
+    conn_builder.keep_alive_interval(Duration::from_secs(20)).mqtt_version(mqtt_version);
+    if mqtt_version >= mqtt::MQTT_VERSION_5 {
+        // MQTT v5 keeps the session only for the expiry interval
+        let mut properties = mqtt::Properties::new();
+        if let Err(error) = properties.push_int(mqtt::PropertyCode::SessionExpiryInterval, SESSION_EXPIRY_INTERVAL) {
+            error!("Cannot set session expiry interval: {}", error);
+        }
+        conn_builder.clean_start(false).properties(properties);
+    } else {
+        conn_builder.clean_session(false);
+    }
+    let conn_opts = conn_builder.finalize();
 
     let message_receiver = mqtt_client.start_consuming();
 
-    info!("Connecting to MQTT broker");
-    match mqtt_client.connect(conn_opts) {
-        Ok(rsp) => {
-            if let Some(conn_rsp) = rsp.connect_response() {
-                info!("Connected to: '{}' with MQTT version {}",
-                         conn_rsp.server_uri, conn_rsp.mqtt_version);
-                if !conn_rsp.session_present {
-                    // Register subscriptions on the server
-                    info!("Subscribing to topics, with requested QoS: {:?}...", qos);
-
-                    match mqtt_client.subscribe_many(&subscriptions, &qos) {
-                        Ok(qosv) => info!("QoS granted: {:?}", qosv),
-                        Err(e) => {
-                            error!("Error subscribing to topics: {:?}", e);
-                            mqtt_client.disconnect(None).unwrap();
-                            process::exit(1);
+    let mut backoff = Backoff::new("mqtt", policy);
+    loop {
+        info!("Connecting to MQTT broker");
+        match mqtt_client.connect(conn_opts.clone()) {
+            Ok(rsp) => {
+                backoff.reset();
+                if let Some(conn_rsp) = rsp.connect_response() {
+                    info!("Connected to: '{}' with MQTT version {}",
+                             conn_rsp.server_uri, conn_rsp.mqtt_version);
+                    if !conn_rsp.session_present {
+                        // Register subscriptions on the server
+                        info!("Subscribing to topics, with requested QoS: {:?}...", qos);
+
+                        match mqtt_client.subscribe_many(&subscriptions, &qos) {
+                            Ok(qosv) => info!("QoS granted: {:?}", qosv),
+                            Err(e) => {
+                                error!("Error subscribing to topics: {:?}", e);
+                                mqtt_client.disconnect(None).unwrap();
+                                process::exit(1);
+                            }
                         }
                     }
                 }
+                break;
+            },
+            Err(e) => {
+                error!("Error connecting to the broker: {:?}", e);
+                backoff.wait();
             }
-        },
-        Err(e) => {
-            error!("Error connecting to the broker: {:?}", e);
-            thread::sleep(Duration::from_millis(3000));
-            info!("Reconnect to MQTT");
-            return mqtt_connect(mqtt_host, client_id, subscriptions, qos)
         }
     }
     ( mqtt_client, message_receiver )
 }
 
-pub fn subscriber(mqtt_host: &str, client_id: &str, subscriptions: &[&str], qos: &[i32], storage_sender: channel::Sender<Command>, max_payload_size: usize) {
+/// Split a gateway message into messages of its devices, devices the gateway is not allowed to speak for are dropped
+fn fan_out(config: &Config, gateway_uid: &str, message: &mqtt::Message, storage_sender: &channel::Sender<Command>) {
+    let gateway = match config.gateways.get(gateway_uid) {
+        Some(gateway) => gateway,
+        None => {
+            warn!("Unknown gateway: {}, the message is dropped", gateway_uid);
+            metrics::increment("gateway_rejected_total");
+            return;
+        }
+    };
+    let content_type = message.properties().get_string(mqtt::PropertyCode::ContentType);
+    let format = payload::select(message.topic(), content_type.as_deref(), config.payload_format(gateway_uid));
+    let devices = match payload::decode(format, message.payload()).and_then(|value| gateway.split(value)) {
+        Ok(devices) => devices,
+        Err(error) => {
+            error!("Cannot decode message of gateway {}: {}", gateway_uid, error);
+            return;
+        }
+    };
+    for (uid, value) in devices {
+        if !gateway.allows(&uid) {
+            warn!("Gateway {} is not allowed to send data of device {}", gateway_uid, uid);
+            metrics::increment("gateway_rejected_total");
+            continue;
+        }
+        let payload = serde_json::to_vec(&value).unwrap_or_default();
+        if let Err(error) = storage_sender.send(Command::Add(uid, payload, Format::Json)) {
+            error!("{}", error);
+        }
+    }
+}
+
+/// Sparkplug alias tables and sequences are kept in the tracker across reconnections, births are not resent to a persistent session
+pub fn subscriber(config: &Config, client_id: &str, subscriptions: &[&str], qos: &[i32], tracker: &mut Option<Tracker>,
+    storage_sender: channel::Sender<Command>, commands_sender: channel::Sender<Command>) {
+    let max_payload_size = config.max_payload_size;
     // Make the connection to the broker
-    let ( mqtt_client, mqtt_message_receiver ) = mqtt_connect(&mqtt_host, &client_id, &subscriptions, &qos);
+    let ( mqtt_client, mqtt_message_receiver ) = mqtt_connect(&config.mqtt_host, &client_id, &subscriptions, &qos, config.mqtt_version, config.reconnect);
 
     // Just loop on incoming messages.
     // If we get a None message, check if we got disconnected,
@@ -103,11 +212,42 @@ pub fn subscriber(mqtt_host: &str, client_id: &str, subscriptions: &[&str], qos:
             Some(message) => {
                 if message.payload().len() > max_payload_size { // DDoS protection
                     error!("Payload size is unacceptable (bigger than {} bytes", max_payload_size);
+                } else if let Some(uid) = commands::response_uid(message.topic()) {
+                    if let Err(error) = commands_sender.send(CommandResponse(uid.to_string(), message.payload_str().to_string())) {
+                        error!("{}", error);
+                    }
+                } else if let (Some(tracker), Some(topic)) = (tracker.as_mut(), sparkplug::Topic::parse(message.topic())) {
+                    match tracker.handle(&topic, message.payload(), Utc::now()) {
+                        Ok(updates) => for update in updates {
+                            let command = match update {
+                                Update::Rebirth(command_topic) => {
+                                    match sparkplug::rebirth_payload(Utc::now()) {
+                                        Ok(payload) => if let Err(error) = mqtt_client.publish(mqtt::Message::new(command_topic, payload, 0)) {
+                                            error!("Cannot request a Sparkplug rebirth: {}", error);
+                                        },
+                                        Err(error) => error!("Cannot encode a Sparkplug rebirth: {}", error),
+                                    }
+                                    continue;
+                                },
+                                Update::Measurements(uid, measurements) => Measurements(uid, measurements),
+                                Update::Death(uid) => Event(sink::Event::new("device_offline", Some(uid), json!({ "topic": message.topic() }))),
+                                Update::Gap(uid, expected, received) => Event(sink::Event::new("sequence_gap", Some(uid),
+                                    json!({ "expected": expected, "received": received }))),
+                            };
+                            if let Err(error) = storage_sender.send(command) {
+                                error!("{}", error);
+                            }
+                        },
+                        Err(error) => error!("Cannot decode Sparkplug payload of {}: {}", message.topic(), error),
+                    }
+                } else if let Some(gateway_uid) = gateway::gateway_uid(message.topic()) {
+                    fan_out(config, gateway_uid, &message, &storage_sender);
                 } else {
                     // device UID
                     let device_id = message.topic().split("/").nth(1).unwrap_or("");
-                    // info!("Send message: STORE({})", message.payload_str());
-                    if let Err(error) = storage_sender.send(Add(device_id.to_string(), message.payload_str().to_string())) {
+                    let content_type = message.properties().get_string(mqtt::PropertyCode::ContentType);
+                    let format = payload::select(message.topic(), content_type.as_deref(), config.payload_format(device_id));
+                    if let Err(error) = storage_sender.send(Add(device_id.to_string(), message.payload().to_vec(), format)) {
                         error!("{}", error);
                     }
                 }
@@ -128,94 +268,167 @@ pub fn subscriber(mqtt_host: &str, client_id: &str, subscriptions: &[&str], qos:
     }
 }
 
+/// A thread which publishes messages (acknowledgments, errors, republished records) over MQTT.
+/// The thread returns in case of connection lost, so it should be restarted by the caller
+pub fn publisher(mqtt_host: &str, client_id: &str, publisher_receiver: channel::Receiver<Command>, policy: ReconnectPolicy) {
+    let create_opts = mqtt::CreateOptionsBuilder::new()
+        .server_uri(mqtt_host)
+        .client_id(client_id)
+        .finalize();
 
-/// A thread which is responsible to store units list and units-devices relationship matrix. 
-/// The storage behaves like a cache between DB and the feeder thread
-/// The function is used in Storage thread
-pub fn units_storage(units_storage_receiver: channel::Receiver<Command>, db_storage_sender: channel::Sender<Command>) {
-    use Command::*;
+    let mqtt_client = match mqtt::Client::new(create_opts) {
+        Ok(client) => client,
+        Err(error) => {
+            error!("Publisher thread: error creating the client: {:?}", error);
+            thread::sleep(Duration::from_millis(3000));
+            return;
+        }
+    };
 
-    // update list of units
-    let mut units = UnitMap::new();
-    let mut units_devices = DevicesUnitsStorage::new();
-    let (units_sender, units_receiver) = channel::bounded(1);
+    let conn_opts = mqtt::ConnectOptionsBuilder::new()
+        .keep_alive_interval(Duration::from_secs(20))
+        .clean_session(true)
+        .finalize();
 
-    if let Err(error) = db_storage_sender.send(LoadUnits(units_sender)) {
-        error!("Storage thread error: {}", error);
-        thread::sleep(Duration::from_millis(5000));
+    let mut backoff = Backoff::new("mqtt_publisher", policy);
+    while let Err(error) = mqtt_client.connect(conn_opts.clone()) {
+        error!("Publisher thread: error connecting to the broker: {:?}", error);
+        backoff.wait();
+    }
+    backoff.reset();
+    info!("Publisher connected to {}", mqtt_host);
+
+    while let Ok(message) = publisher_receiver.recv() {
+        use Command::*;
+        match message {
+            Publish(topic, payload) => {
+                if let Err(error) = mqtt_client.publish(mqtt::Message::new(topic, payload, 1)) {
+                    error!("Publisher thread error: {}", error);
+                    if !mqtt_client.is_connected() {
+                        return;
+                    }
+                }
+            },
+            Republish(topic, payload, qos, retain) => {
+                let message = if retain {
+                    mqtt::Message::new_retained(topic, payload, qos)
+                } else {
+                    mqtt::Message::new(topic, payload, qos)
+                };
+                if let Err(error) = mqtt_client.publish(message) {
+                    error!("Publisher thread error: {}", error);
+                    if !mqtt_client.is_connected() {
+                        return;
+                    }
+                }
+            },
+            Disconnect => {
+                break;
+            },
+            _ => {
+                warn!("Publisher thread: unimplemented message: {:?}", message);
+            }
+        }
+    }
 
-        // restart the thread
-        return;
+    if mqtt_client.is_connected() {
+        if let Err(error) = mqtt_client.disconnect(None) {
+            error!("Publisher thread error: {}", error);
+        }
     }
-    // update list of units
-    if let Ok(message) = units_receiver.recv() {
-        units = message;
-        info!("Loaded {} units", units.len());
+}
+
+
+/// Request devices list from DB thread
+fn load_devices(db_storage_sender: &channel::Sender<Command>) -> Option<DeviceMap> {
+    let (sender, receiver) = channel::bounded(1);
+    if let Err(error) = db_storage_sender.send(Command::Load(sender)) {
+        error!("Storage thread error: {}", error);
+        return None;
     }
+    receiver.recv().ok()
+}
 
-    let (devices_units_sender, devices_units_receiver) = channel::bounded(1);
-    if let Err(error) = db_storage_sender.send(LoadDevicesUnits(devices_units_sender)) {
+/// Request units list from DB thread
+fn load_units(db_storage_sender: &channel::Sender<Command>) -> Option<UnitMap> {
+    let (sender, receiver) = channel::bounded(1);
+    if let Err(error) = db_storage_sender.send(Command::LoadUnits(sender)) {
         error!("Storage thread error: {}", error);
-        thread::sleep(Duration::from_millis(5000));
+        return None;
+    }
+    receiver.recv().ok()
+}
 
-        // restart the thread
-        return;
+/// Request units-devices relationship matrix from DB thread
+fn load_devices_units(db_storage_sender: &channel::Sender<Command>) -> Option<DevicesUnitsStorage> {
+    let (sender, receiver) = channel::bounded(1);
+    if let Err(error) = db_storage_sender.send(Command::LoadDevicesUnits(sender)) {
+        error!("Storage thread error: {}", error);
+        return None;
     }
+    receiver.recv().ok()
+}
+
+/// A thread which is responsible to store units list and units-devices relationship matrix. 
+/// The storage behaves like a cache between DB and the feeder thread
+/// The function is used in Storage thread
+pub fn units_storage(units_storage_receiver: channel::Receiver<Command>, db_storage_sender: channel::Sender<Command>, routes: Vec<Route>) {
+    use Command::*;
+
+    // update list of units
+    let mut units = load_units(&db_storage_sender).unwrap_or_else(|| {
+        error!("Cannot load units, use empty list instead!");
+        UnitMap::new()
+    });
+    info!("Loaded {} units", units.len());
 
     // update list of relationship between units and devices
-    if let Ok(message) = devices_units_receiver.recv() {
-        units_devices = message;
-        info!("Loaded {} units-devices relationships", units_devices.rows_count());
-        info!("Units_Devices: {:?}", units_devices);
-    } else {
+    let mut units_devices = load_devices_units(&db_storage_sender).unwrap_or_else(|| {
         error!("Cannot load units-devices relationship matrix, use empty list instead!");
-    }
+        DevicesUnitsStorage::new()
+    });
+    info!("Loaded {} units-devices relationships", units_devices.rows_count());
+    info!("Units_Devices: {:?}", units_devices);
+    // links added since the last resync, DB could have not got them yet
+    let mut recent_links = BTreeSet::new();
 
     while let Ok(message) = units_storage_receiver.recv() {
         match message {
             LinkDeviceToUnit(device_id, unit_id) => {
-                units_devices.add(unit_id, device_id, true);
-                // create a record in DB
-                if let Err(error) = db_storage_sender.send(LinkDeviceToUnit(device_id, unit_id)) {
-                    error!("Units Storage thread error: {}", error);
-                }
-            },
-            CheckDeviceUnit(device_id, unit_id, sender) => {
-                if let Some(_) = units_devices.get(unit_id, device_id) {
-                    if let Err(error) = sender.send(true) {
-                        error!("Units Storage thread error: {}", error);
-                    }
-                } else {
-                    if let Err(error) = sender.send(false) {
+                // check-and-set: messages are handled one by one, so concurrent processing threads cannot link twice
+                if units_devices.get(unit_id, device_id).is_none() {
+                    units_devices.add(unit_id, device_id, true);
+                    recent_links.insert((unit_id, device_id));
+                    // create a record in DB
+                    if let Err(error) = db_storage_sender.send(LinkDeviceToUnit(device_id, unit_id)) {
                         error!("Units Storage thread error: {}", error);
                     }
                 }
             },
             GetUnit(name, sender) => {
                 if let Some(id) = units.get(&name) {
-                    if let Err(error) = sender.send(Some(*id)) {
+                    if let Err(error) = sender.send(Ok(*id)) {
                         error!("Units Storage thread error: {}", error);
                     }
                 } else {
                     // add unit to the list and to DB
-                    let mut unit_id:Option<usize> = None;
                     warn!("Cannot find Unit named: {}. Create unit record in DB", &name);
                     let (units_sender, units_receiver) = channel::bounded(1);
                     if let Err(error) = db_storage_sender.send(CreateUnit(name.clone(), units_sender)) {
                         error!("Storage thread error: {}", error);
                     }
                     
-                    // get response from DB
-                    if let Ok(message) = units_receiver.recv() {
-                        match message {
-                            Some(id) => {
-                                units.insert(name, id);
-                            },
-                            None => {
-                                error!("Unit ID is unknown!");
-                            }
+                    // get response from DB, DBStorage answers every request unless it is down
+                    let unit_id = units_receiver.recv()
+                        .unwrap_or_else(|_| Err("DB storage is not available".to_string()));
+                    match &unit_id {
+                        Ok(id) => {
+                            sink::notify(&routes, sink::Event::new("unit_created", None, json!({ "unit_id": id, "unit": name })));
+                            units.insert(name, *id);
+                        },
+                        Err(error) => {
+                            error!("Cannot create unit {}: {}", &name, error);
                         }
-                        unit_id = message;
                     }
 
                     if let Err(error) = sender.send(unit_id) {
@@ -223,6 +436,23 @@ pub fn units_storage(units_storage_receiver: channel::Receiver<Command>, db_stor
                     }
                 };
             },
+            ActivateUnit(id, name) => {
+                if !units.contains_key(&name) {
+                    sink::notify(&routes, sink::Event::new("unit_created", None, json!({ "unit_id": id, "unit": name })));
+                }
+                units.insert(name, id);
+            },
+            Resync => {
+                if let Some(loaded) = load_units(&db_storage_sender) {
+                    let diff = Diff::new(&units, &loaded);
+                    diff.report("units");
+                    diff.apply(&mut units);
+                }
+                if let Some(loaded) = load_devices_units(&db_storage_sender) {
+                    resync::resync_links(&mut units_devices, &loaded, &recent_links);
+                    recent_links.clear();
+                }
+            },
             _ => {
                 warn!("Units Storage thread: unimplemented message: {:?}", message);
             }
@@ -230,128 +460,253 @@ pub fn units_storage(units_storage_receiver: channel::Receiver<Command>, db_stor
     }
 }
 
-pub fn storage(storage_receiver: channel::Receiver<Command>, db_storage_sender: channel::Sender<Command>) {
-    let mut devices = DeviceMap::new(); // if ID from database provided, then the device is active, otherwise it's NONE
-    // load devices from DB
-    use Command::*;
+/// Id of an active device, inactive and unknown devices are reported
+fn active_device(devices: &DeviceMap, uid: &str) -> Option<usize> {
+    match devices.get(uid) {
+        Some(Some(id)) => Some(*id),
+        Some(None) => {
+            warn!("Device: {} is inactive", uid);
+            None
+        },
+        None => {
+            warn!("No device with UID: {} in devices, an user needs to add it at first", uid);
+            None
+        }
+    }
+}
+
+/// Settings and state of the processing stage shared by processing threads
+pub struct Stage {
+    pub decoder: Decoder,
+    pub validator: Validator,
+    /// Redelivered messages are stored again if not set
+    pub dedup: Option<Arc<Deduplicator>>,
+    pub deadband: Deadband,
+    pub derived: Derived,
+}
 
-    let (s_sender, s_receiver) = channel::bounded(1);
+/// Resolves units of decoded measurements and dispatches records to sinks, used by processing threads
+#[derive(Clone)]
+struct Resolver {
+    units_storage_sender: channel::Sender<Command>,
+    publisher_sender: Option<channel::Sender<Command>>,
+    routes: Vec<Route>,
+    stage: Arc<Stage>,
+}
+
+impl Resolver {
+    fn reject(&self, uid: &str, payload: Value) {
+        reject(&self.publisher_sender, &self.routes, uid, payload);
+    }
+
+    /// Key of the message if dedup is configured, Err if the message is a duplicate
+    fn dedup_key(&self, uid: &str, message_id: &Value, payload: &[u8]) -> std::result::Result<Option<String>, ()> {
+        match self.stage.dedup.as_ref().and_then(|dedup| dedup.key(uid, message_id, payload).map(|key| (dedup, key))) {
+            Some((dedup, key)) if dedup.is_duplicate(&key) => Err(()),
+            Some((_, key)) => Ok(Some(key)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check the value by validation rules, a rejected value is reported
+    fn validate(&self, device_id: usize, uid: &str, message_id: &Value, unit: &str, value: &Value) -> bool {
+        match self.stage.validator.validate(device_id, uid, unit, value) {
+            Ok(()) => true,
+            Err(reason) => {
+                warn!("Processing thread: value {} of unit {} of device {} is rejected: {}", value, unit, uid, reason);
+                self.reject(uid, json!({ "id": message_id, "unit": unit, "value": value, "error": "invalid value", "details": reason }));
+                false
+            }
+        }
+    }
+
+    /// Unit id by name, the device is linked to the unit
+    fn unit_id(&self, device_id: usize, uid: &str, message_id: &Value, unit: &str) -> Option<usize> {
+        use Command::*;
+        let (u_sender, u_receiver) = channel::bounded(1);
+
+        // find unit_id by name
+        if let Err(error) = self.units_storage_sender.send(GetUnit(unit.to_string(), u_sender)) {
+            error!("Processing thread error: {}", error);
+        }
+
+        // Units Storage answers every request unless it is down
+        match u_receiver.recv().unwrap_or_else(|_| Err("units storage is not available".to_string())) {
+            Ok(unit_id) => {
+                // link the device to the unit, Units Storage checks and sets the link in one step
+                if let Err(error) = self.units_storage_sender.send(LinkDeviceToUnit(device_id, unit_id)) {
+                    error!("Processing thread error: {}", error);
+                }
+                Some(unit_id)
+            },
+            Err(reason) => {
+                error!("Processing thread error: Cannot find unit_id in DB and cannot create a new record: {}", reason);
+                self.reject(uid, json!({ "id": message_id, "unit": unit, "error": "unknown unit", "details": reason }));
+                None
+            }
+        }
+    }
+
+    fn resolve(&self, device_id: usize, uid: String, message_id: Value, dedup_key: Option<String>, measurements: Vec<Measurement>, now: DateTime<Utc>) {
+        // iterate measurements and find unit ids
+        let mut records = Vec::new();
+
+        for Measurement { unit, value, timestamp } in measurements {
+            if value.is_null() {
+                error!("Processing thread error: invalid value for unit {}", unit);
+                self.reject(&uid, json!({ "id": message_id, "unit": unit, "error": "invalid value" }));
+                continue;
+            }
+            if !self.validate(device_id, &uid, &message_id, &unit, &value) {
+                continue;
+            }
+            if let Some(unit_id) = self.unit_id(device_id, &uid, &message_id, &unit) {
+                records.push(Record { device_id, uid: uid.clone(), unit_id, unit,
+                    value, timestamp: timestamp.unwrap_or(now) });
+            }
+        }
+        // derived units are validated and get their ids and links like the units of the message
+        for (unit, value, timestamp) in self.stage.derived.evaluate(&uid, &records) {
+            if !self.validate(device_id, &uid, &message_id, &unit, &value) {
+                continue;
+            }
+            if let Some(unit_id) = self.unit_id(device_id, &uid, &message_id, &unit) {
+                records.push(Record { device_id, uid: uid.clone(), unit_id, unit, value, timestamp });
+            }
+        }
+        // nothing to store, the device learns it without an empty DB write
+        if records.is_empty() {
+            if let (Some(dedup), Some(key)) = (&self.stage.dedup, &dedup_key) {
+                dedup.forget(key);
+            }
+            report(&self.publisher_sender, ack_topic(&uid), json!({ "id": message_id, "status": "rejected", "records": 0 }));
+            return;
+        }
+        // unchanged values are not stored, but they are seen
+        let records = self.stage.deadband.filter(records);
+        let ack = Ack { uid, message_id, dedup_key };
+        if records.is_empty() {
+            self.confirm(&ack);
+            report(&self.publisher_sender, ack_topic(&ack.uid), json!({ "id": ack.message_id, "status": "unchanged", "records": 0 }));
+            return;
+        }
+        if sink::dispatch(&self.routes, device_id, &records, &ack) {
+            return;
+        }
+        // without a Store command the message is done once it is dispatched
+        self.confirm(&ack);
+        if self.routes.iter().any(|route| route.target == Target::Store) {
+            report(&self.publisher_sender, ack_topic(&ack.uid), json!({ "id": ack.message_id, "status": "filtered", "records": 0 }));
+        }
+    }
+
+    fn confirm(&self, ack: &Ack) {
+        if let (Some(dedup), Some(key)) = (&self.stage.dedup, &ack.dedup_key) {
+            dedup.confirm(key);
+        }
+    }
+}
+
+pub fn storage(storage_receiver: channel::Receiver<Command>, db_storage_sender: channel::Sender<Command>, publisher_sender: Option<channel::Sender<Command>>, routes: Vec<Route>, stage: Arc<Stage>) {
+    use Command::*;
 
     let (units_storage_sender, units_storage_receiver) = channel::unbounded();
 
     let db_storage_sender_for_units = db_storage_sender.clone();
+    let routes_for_units = routes.clone();
     thread::spawn(move || {
-        units_storage(units_storage_receiver, db_storage_sender_for_units);
+        units_storage(units_storage_receiver, db_storage_sender_for_units, routes_for_units);
     });
+    let resolver = Resolver { units_storage_sender: units_storage_sender.clone(), publisher_sender, routes: routes.clone(), stage };
     
-    if let Err(error) = db_storage_sender.send(Load(s_sender)) {
-        error!("Storage thread error: {}", error);
-        thread::sleep(Duration::from_millis(5000));
-
-        // restart the thread
-        return;
-    }
-
-    // update device list from DB
-    if let Ok(message) = s_receiver.recv() {
-        devices = message;
-        info!("Loaded {} devices", devices.len());
-    }
+    // load devices from DB
+    // if ID from database provided, then the device is active, otherwise it's NONE
+    let mut devices = match load_devices(&db_storage_sender) {
+        Some(message) => {
+            info!("Loaded {} devices", message.len());
+            message
+        },
+        None => {
+            thread::sleep(Duration::from_millis(5000));
+            // restart the thread
+            return;
+        }
+    };
 
     
     while let Ok(message) = storage_receiver.recv() {
         
 
         match message {
-            Add(uid, payload) => {
+            Add(uid, payload, format) => {
                 // info!("Store for {}, message: {}", uid, payload);
-                match devices.get(&uid) {
-                    Some(None) => {
-                        warn!("Device: {} is inactive", &uid);
-                    },
-                    Some(Some(id)) => {
-                        // send payload to DB stream
-                        // let process_db_storage_tx = db_storage_tx.clone();
-                        let device_id = *id;
-
-                        // info!("Device id: {} message: {}", &id, &payload);
-                        // parse JSON payload to Value struct and pass it to DB thread
-                        let process_db_storage_sender = db_storage_sender.clone();
-                        let process_units_storage_sender = units_storage_sender.clone();
-                        
-                        // start a processing thread which should parse the received payload and prepare data to be put in DB
-                        thread::spawn(move || {
-                            match serde_json::from_str::<Value>(&payload) {
-                                Ok(value) => {
-                                    if let Value::Object(map) = value {
-                                        // info!("Parsed: {:?}", map);
-                                        // iterate the map and find unit ids
-                                        let mut records:BTreeMap<usize, String> = BTreeMap::new();
-
-                                        for (unit, value) in &map {
-                                            //find unit id
-                                            let (u_sender, u_receiver) = channel::bounded(1);
-
-                                            // find unit_id by name
-                                            if let Err(error) = process_units_storage_sender.send(GetUnit(unit.clone(), u_sender)) {
-                                                error!("Processing thread error: {}", error);
-                                            }
-
-                                            let mut unit_id:Option<usize> = None;  
-
-                                            // update device list from DB
-                                            if let Ok(message) = u_receiver.recv() {
-                                                match message {
-                                                    Some(u_id) => {
-                                                        unit_id = Some(u_id); 
-                                                        records.insert(u_id, value.to_string());
-                                                    },
-                                                    None => {
-                                                        error!("Processing thread error: Cannot find unit_id in DB and cannot create a new record");
-                                                    }
-                                                }    
-                                            }
-
-                                            // check if the device can send measurement with this unit
-                                            let (ud_sender, ud_receiver) = channel::bounded(1);
-                                            if let Some(unit_id) = unit_id {
-                                                if let Err(error) = process_units_storage_sender.send(CheckDeviceUnit(device_id, unit_id, ud_sender)) {
-                                                    error!("Processing thread error: {}", error);
-                                                }
-
-                                                if let Ok(message) = ud_receiver.recv() {
-                                                    if !message {
-                                                        if let Err(error) = process_units_storage_sender.send(LinkDeviceToUnit(device_id, unit_id)) {
-                                                            error!("Processing thread error: {}", error);
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        if let Err(error) = process_db_storage_sender.send(Store(device_id, records)) {
-                                            error!("{}", error);
-                                        }
-                                    } else {
-                                        error!("Storage thread: the message should be a map");
-                                    }
-                                },
-                                Err(error) => {
-                                    error!("Cannot parse the payload string due to error: {}", error);
+                if let Some(device_id) = active_device(&devices, &uid) {
+                    let process_resolver = resolver.clone();
+
+                    // start a processing thread which should parse the received payload and prepare data to be put in DB
+                    thread::spawn(move || {
+                        let value = match process_resolver.stage.decoder.decode(format, &uid, &payload) {
+                            Ok(value) => value,
+                            Err(error) => {
+                                error!("Cannot parse the payload string due to error: {}", error);
+                                process_resolver.reject(&uid, json!({ "id": Value::Null, "error": "cannot parse the payload" }));
+                                return;
+                            }
+                        };
+                        let now = Utc::now();
+                        match process_resolver.stage.decoder.measurements(&uid, value, now) {
+                            Ok((message_id, measurements)) => match process_resolver.dedup_key(&uid, &message_id, &payload) {
+                                Ok(dedup_key) => process_resolver.resolve(device_id, uid, message_id, dedup_key, measurements, now),
+                                Err(()) => {
+                                    info!("Duplicate message {} of device {} is dropped", message_id, uid);
+                                    report(&process_resolver.publisher_sender, ack_topic(&uid), json!({ "id": message_id, "status": "duplicate" }));
                                 }
+                            },
+                            Err(error) => {
+                                error!("Storage thread: {}", error);
+                                process_resolver.reject(&uid, json!({ "id": Value::Null, "error": error }));
                             }
-                        });
-                    },
-                    None => {
-                        warn!("No device with UID: {} in devices, an user needs to add it at first", &uid);
-                    }
+                        }
+                    });
+                }
+            },
+            Measurements(uid, measurements) => {
+                if let Some(device_id) = active_device(&devices, &uid) {
+                    let process_resolver = resolver.clone();
+                    thread::spawn(move || {
+                        process_resolver.resolve(device_id, uid, Value::Null, None, measurements, Utc::now());
+                    });
                 }
             },
+            Event(event) => {
+                sink::notify(&routes, event);
+            },
             Disconnect => {
                 return;
             },
+            Resync => {
+                // events could be missed during reconnection, reload caches from DB
+                if let Some(loaded) = load_devices(&db_storage_sender) {
+                    let diff = Diff::new(&devices, &loaded);
+                    diff.report("devices");
+                    diff.apply(&mut devices);
+                }
+                if let Err(error) = units_storage_sender.send(Resync) {
+                    error!("Storage thread error: {}", error);
+                }
+            },
             Activate(id, uid) => {
                 info!("Activate device: {} with id: {}", &uid, id);
-                devices.insert(uid, Some(id));
+                // Phoenix sends "updated" for any change of an active device
+                if devices.insert(uid.clone(), Some(id)).flatten().is_none() {
+                    sink::notify(&routes, sink::Event::new("device_activated", Some(uid), json!({ "device_id": id })));
+                }
+            },
+            Deactivate(id, uid) => {
+                info!("Deactivate device: {} with id: {}", &uid, id);
+                if devices.insert(uid.clone(), None).flatten().is_some() {
+                    sink::notify(&routes, sink::Event::new("device_deactivated", Some(uid), json!({ "device_id": id })));
+                }
             },
             ActivateUnit(id, name) => {
                 info!("Activate unit: {} with id: {}", &name, id);
@@ -367,92 +722,302 @@ pub fn storage(storage_receiver: channel::Receiver<Command>, db_storage_sender:
     }
 }
 
-pub fn db_storage(db_host: &str, db_storage_receiver: channel::Receiver<Command>) {
-    let pool = Pool::new(db_host).unwrap();
-    let mut conn = pool.get_conn().unwrap();
+/// Execute Store, LinkDeviceToUnit or Seen command.
+/// The command is returned back in case of transient error, so it could be re-issued later
+fn execute_write(pool: &Pool, message: Command, publisher_sender: &Option<channel::Sender<Command>>, dedup: &Option<Arc<Deduplicator>>) -> std::result::Result<(), Command> {
+    use Command::*;
+    let res = pool.get_conn().map_err(db::WriteError::from).and_then(|mut conn| match &message {
+        Store(id, records, _) => {
+            info!("Put {} records to DB with id: {:?}", records.len(), id);
+            // all records of the message are committed at once, so the acknowledgment is for the whole message
+            db::store_records(&mut conn, *id, records)
+        },
+        // links and last seen times are upserts, they could be re-issued after a lost commit
+        LinkDeviceToUnit(device_id, unit_id) => db::link_device_to_unit(&mut conn, *device_id, *unit_id).map_err(db::WriteError::from),
+        Seen(seen) => db::update_last_seen(&mut conn, seen).map_err(db::WriteError::from),
+        _ => Ok(()),
+    });
+    match res {
+        Ok(()) => {
+            if let Store(_, records, ack) = &message {
+                if let (Some(dedup), Some(key)) = (dedup, &ack.dedup_key) {
+                    dedup.confirm(key);
+                }
+                report(publisher_sender, ack_topic(&ack.uid),
+                    json!({ "id": ack.message_id, "status": "stored", "records": records.len() }));
+            }
+            Ok(())
+        },
+        Err(error) if error.committing && db::is_transient(&error.error) => {
+            // the records could be stored, a re-issued insert would store them twice
+            error!("DBStorage thread: {}, the records could be stored, the command is not re-issued", error);
+            if let Store(_, _, ack) = &message {
+                report(publisher_sender, ack_topic(&ack.uid), json!({ "id": ack.message_id, "status": "unknown" }));
+            }
+            Ok(())
+        },
+        Err(error) if db::is_transient(&error.error) => {
+            warn!("DBStorage thread: {}, the command will be re-issued", error);
+            Err(message)
+        },
+        Err(error) => {
+            error!("DBStorage thread error: {}", error);
+            fail_write(message, publisher_sender, dedup);
+            Ok(())
+        }
+    }
+}
+
+/// Report a write which cannot be done, the device could send the message again
+fn fail_write(message: Command, publisher_sender: &Option<channel::Sender<Command>>, dedup: &Option<Arc<Deduplicator>>) {
+    if let Command::Store(_, _, ack) = message {
+        if let (Some(dedup), Some(key)) = (dedup, &ack.dedup_key) {
+            dedup.forget(key);
+        }
+        report(publisher_sender, ack_topic(&ack.uid),
+            json!({ "id": ack.message_id, "status": "failed", "records": 0 }));
+    }
+}
+
+pub fn db_storage(db_host: &str, db_storage_receiver: channel::Receiver<Command>, publisher_sender: Option<channel::Sender<Command>>, dedup: Option<Arc<Deduplicator>>, policy: ReconnectPolicy, retry_queue_size: usize) {
+    let pool = db::connect(db_host, policy);
 
-    while let Ok(message) = db_storage_receiver.recv() {
+    // Store, LinkDeviceToUnit and Seen commands failed due to transient errors, re-issued in order with backoff
+    let mut retry_queue: VecDeque<Command> = VecDeque::new();
+    let mut retry_backoff = Backoff::new("mysql", policy);
+    let mut retry_at = Instant::now();
+
+    loop {
         use Command::*;
 
+        let message = if retry_queue.is_empty() {
+            match db_storage_receiver.recv() {
+                Ok(message) => Some(message),
+                Err(_) => return,
+            }
+        } else {
+            match db_storage_receiver.recv_timeout(retry_at.saturating_duration_since(Instant::now())) {
+                Ok(message) => Some(message),
+                Err(channel::RecvTimeoutError::Timeout) => None,
+                Err(channel::RecvTimeoutError::Disconnected) => return,
+            }
+        };
+
         match message {
-            Load(sender) => {
-                let mut devices = DeviceMap::new(); 
-                let _list_of_devices = conn
-                    // TODO: add where active is true
-                    .query_map("SELECT id, uid FROM devices;", |(id, uid)| {
-                        // println!("Device info: (id: {}, uid: {})", id, &uid);
-                        // if device is active
-                        devices.insert(uid, Some(id));
-                });
-                if let Err(error) = sender.send(devices) {
-                    error!("DBStorage thread error: {}", error);
-                }
-            },
-            Store(id, map) => {
-                info!("Put {:?} to DB with id: {:?}", map, id);
-                let utc_timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-                for (unit_id, value) in map {
-                    let res = conn.exec_drop("INSERT INTO records (device_id, unit_id, value, inserted_at, updated_at) VALUES (:device_id, :unit_id, :value, :inserted_at, :updated_at)",
-                        params! { "device_id" => id, "unit_id" => unit_id, "value" => value, "inserted_at" => &utc_timestamp, "updated_at" => &utc_timestamp});
-                    if let Err(error) = res {
-                        error!("DBStorage thread error: {}", error);
+            Some(message @ Store(..)) | Some(message @ LinkDeviceToUnit(..)) | Some(message @ Seen(..)) => {
+                // while DB is unavailable new writes wait in the queue to keep the order
+                let failed = if retry_queue.is_empty() {
+                    execute_write(&pool, message, &publisher_sender, &dedup).err()
+                } else {
+                    Some(message)
+                };
+                if let Some(message) = failed {
+                    if retry_queue.is_empty() {
+                        retry_at = Instant::now() + retry_backoff.next_delay();
+                    }
+                    if retry_queue.len() >= retry_queue_size {
+                        error!("DBStorage thread: retry queue is full, drop the oldest command");
+                        if let Some(dropped) = retry_queue.pop_front() {
+                            fail_write(dropped, &publisher_sender, &dedup);
+                        }
                     }
+                    retry_queue.push_back(message);
                 }
             },
-            LoadDevicesUnits(sender) => {
-                let mut devices_units = DevicesUnitsStorage::new();
-                let _list_of_records = conn
-                    .query_map("SELECT unit_id, device_id FROM devices_units;", |(unit_id, device_id)| {
-                        // get units vector
-                        devices_units.add(unit_id, device_id, true);
-                        info!("Record: {:?} : {:?} ", unit_id, device_id);
-                });
-                if let Err(error) = sender.send(devices_units) {
-                    error!("DBStorage thread error: {}", error);
+            Some(Load(sender)) => {
+                match db::with_retry(&pool, policy, db::load_devices) {
+                    Ok(devices) => {
+                        if let Err(error) = sender.send(devices) {
+                            error!("DBStorage thread error: {}", error);
+                        }
+                    },
+                    // the sender is dropped, so the requester knows the load failed
+                    Err(error) => error!("DBStorage thread: cannot load devices: {}", error),
                 }
             },
-            LoadUnits(sender) => {
-                let mut units = UnitMap::new();
-                let _list_of_units = conn
-                    .query_map("SELECT id, name FROM units;", |(id, name)| {
-                        units.insert(name, id);
-                });
-                if let Err(error) = sender.send(units) {
-                    error!("DBStorage thread error: {}", error);
+            Some(LoadDevicesUnits(sender)) => {
+                match db::with_retry(&pool, policy, db::load_devices_units) {
+                    Ok(devices_units) => {
+                        if let Err(error) = sender.send(devices_units) {
+                            error!("DBStorage thread error: {}", error);
+                        }
+                    },
+                    Err(error) => error!("DBStorage thread: cannot load units-devices relationships: {}", error),
                 }
             },
-            CreateUnit(name, sender) => {
-                let utc_timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-                let _res = conn.exec_first::<u32, _, _>("INSERT INTO units (name, inserted_at, updated_at) VALUES (:name, :inserted_at, :updated_at);", 
-                    params! { "name" => name, "inserted_at" => &utc_timestamp,
-                                "updated_at" => utc_timestamp});
-
-                // Get ID of the created record. In case of PostgreSQL could be omitted as queries in PostgreSQL return ID of the record
-                let res:mysql::Result<Option<usize>> = conn.query_first("SELECT LAST_INSERT_ID();");
-
-                if let Ok(unit_id) = res {
-                    info!("Created unit record with ID: {:?}", &unit_id);
-                    if let Err(error) = sender.send(unit_id) {
-                        error!("DBStorage thread error: {}", error);
-                    }
-                } else {
-                    error!("DBStorage thread: Cannot create unit record in DB");
+            Some(LoadUnits(sender)) => {
+                match db::with_retry(&pool, policy, db::load_units) {
+                    Ok(units) => {
+                        if let Err(error) = sender.send(units) {
+                            error!("DBStorage thread error: {}", error);
+                        }
+                    },
+                    Err(error) => error!("DBStorage thread: cannot load units: {}", error),
                 }
             },
-            LinkDeviceToUnit(device_id, unit_id) => {
-                info!("Create a record in devices_units table: (device_id: {}, unit_id: {}", device_id, unit_id);
-                let utc_timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-                let res = conn.exec_first::<u32, _, _,>("INSERT INTO devices_units (device_id, unit_id, inserted_at, updated_at) 
-                            VALUES (:device_id, :unit_id, :inserted_at, :updated_at);", 
-                    params! { "device_id" => device_id, "unit_id" => unit_id, "inserted_at" => &utc_timestamp, "updated_at" => utc_timestamp });
-                if let Err(error) = res {
+            Some(CreateUnit(name, sender)) => {
+                // always answer, Units Storage waits for the result
+                let unit_id = db::with_retry(&pool, policy, |conn| db::create_unit(conn, &name))
+                    .map_err(|error| error.to_string())
+                    .and_then(|id| id.ok_or_else(|| format!("no id of unit {} after the insert", name)))
+                    .map_err(|error| {
+                        error!("DBStorage thread: Cannot create unit record in DB: {}", error);
+                        error
+                    });
+                info!("Unit {} has ID: {:?}", &name, &unit_id);
+                if let Err(error) = sender.send(unit_id) {
                     error!("DBStorage thread error: {}", error);
                 }
-            }
-            _ => {
+            },
+            Some(_) => {
                 warn!("DBStorage thread: unimplemented command!");
             },
+            None => {},
+        }
+
+        // re-issue failed writes
+        while !retry_queue.is_empty() && Instant::now() >= retry_at {
+            if let Some(message) = retry_queue.pop_front() {
+                if let Err(message) = execute_write(&pool, message, &publisher_sender, &dedup) {
+                    retry_queue.push_front(message);
+                    retry_at = Instant::now() + retry_backoff.next_delay();
+                } else if retry_queue.is_empty() {
+                    retry_backoff.reset();
+                }
+            }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::validation::{Rule, ValidationConfig};
+    use super::super::deadband::DeadbandConfig;
+    use super::super::derived::DerivedConfig;
+
+    /// DBStorage stand-in: empty caches, units are created with ids from 11, "broken" cannot be created
+    fn fake_db_storage(receiver: channel::Receiver<Command>, commands: channel::Sender<String>) {
+        let mut next_id = 10;
+        while let Ok(message) = receiver.recv() {
+            match message {
+                Command::LoadUnits(sender) => { sender.send(UnitMap::new()).unwrap(); },
+                Command::LoadDevicesUnits(sender) => { sender.send(DevicesUnitsStorage::new()).unwrap(); },
+                Command::CreateUnit(name, sender) => {
+                    commands.send(format!("create {}", name)).unwrap();
+                    if name == "broken" {
+                        sender.send(Err("table is read only".to_string())).unwrap();
+                    } else {
+                        next_id += 1;
+                        sender.send(Ok(next_id)).unwrap();
+                    }
+                },
+                Command::LinkDeviceToUnit(device_id, unit_id) => { commands.send(format!("link {} {}", device_id, unit_id)).unwrap(); },
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_units_storage_creates_unit_once() {
+        let (db_sender, db_receiver) = channel::unbounded();
+        let (commands_sender, commands) = channel::unbounded();
+        thread::spawn(move || fake_db_storage(db_receiver, commands_sender));
+        let (units_sender, units_receiver) = channel::unbounded();
+        thread::spawn(move || units_storage(units_receiver, db_sender, Vec::new()));
+
+        // processing threads ask for the same new unit at once
+        let requests: Vec<channel::Receiver<UnitResult>> = (0..4).map(|_| {
+            let (sender, receiver) = channel::bounded(1);
+            units_sender.send(Command::GetUnit("co2".to_string(), sender)).unwrap();
+            receiver
+        }).collect();
+        for receiver in requests {
+            assert_eq!(receiver.recv().unwrap(), Ok(11));
+        }
+        // a unit which cannot be created is answered with the error
+        let (sender, receiver) = channel::bounded(1);
+        units_sender.send(Command::GetUnit("broken".to_string(), sender)).unwrap();
+        assert!(receiver.recv().unwrap().is_err());
+        drop(units_sender);
+
+        let sent: Vec<String> = commands.iter().take(2).collect();
+        assert_eq!(sent, vec!["create co2", "create broken"]);
+        assert!(commands.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_units_storage_links_device_once() {
+        let (db_sender, db_receiver) = channel::unbounded();
+        let (commands_sender, commands) = channel::unbounded();
+        thread::spawn(move || fake_db_storage(db_receiver, commands_sender));
+        let (units_sender, units_receiver) = channel::unbounded();
+        thread::spawn(move || units_storage(units_receiver, db_sender, Vec::new()));
+
+        // processing threads link the device to a new unit at once
+        for _ in 0..3 {
+            units_sender.send(Command::LinkDeviceToUnit(1, 11)).unwrap();
+        }
+        units_sender.send(Command::LinkDeviceToUnit(2, 11)).unwrap();
+        units_sender.send(Command::LinkDeviceToUnit(1, 11)).unwrap();
+        drop(units_sender);
+
+        let sent: Vec<String> = commands.iter().take(2).collect();
+        assert_eq!(sent, vec!["link 1 11", "link 2 11"]);
+        assert!(commands.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    /// Resolver with a units storage giving ids by the order of requests and a DB route
+    fn resolver(stage: Stage) -> (Resolver, channel::Receiver<Command>) {
+        let (units_storage_sender, units_storage_receiver) = channel::unbounded();
+        thread::spawn(move || {
+            let mut units = UnitMap::new();
+            while let Ok(message) = units_storage_receiver.recv() {
+                if let Command::GetUnit(name, sender) = message {
+                    let next_id = units.len() + 1;
+                    sender.send(Ok(*units.entry(name).or_insert(next_id))).unwrap();
+                }
+            }
+        });
+        let (db_sender, db_receiver) = channel::unbounded();
+        let routes = vec![Route { name: "mysql", filter: sink::Filter::default(), target: Target::Store, events: false, sender: db_sender }];
+        (Resolver { units_storage_sender, publisher_sender: None, routes, stage: Arc::new(stage) }, db_receiver)
+    }
+
+    #[test]
+    fn test_rejected_message_is_not_stored() {
+        let mut validation = ValidationConfig::default();
+        validation.units.insert("temperature".to_string(), Rule { max: Some(125.0), ..Rule::default() });
+        let stage = Stage { decoder: Decoder::default(), validator: Validator::new(validation), dedup: None,
+            deadband: Deadband::new(DeadbandConfig::default()), derived: Derived::new(&DerivedConfig::default()).unwrap() };
+        let (resolver, db_receiver) = resolver(stage);
+
+        let measurements = vec![Measurement { unit: "temperature".to_string(), value: json!(300), timestamp: None }];
+        resolver.resolve(1, "sensor".to_string(), json!(1), None, measurements, Utc::now());
+        drop(resolver);
+        assert!(db_receiver.recv().is_err());
+    }
+
+    #[test]
+    fn test_derived_values_are_validated() {
+        let mut validation = ValidationConfig::default();
+        validation.units.insert("power".to_string(), Rule { max: Some(1000.0), ..Rule::default() });
+        let mut derived = DerivedConfig::default();
+        derived.units.insert("power".to_string(), "voltage * current".to_string());
+        let stage = Stage { decoder: Decoder::default(), validator: Validator::new(validation), dedup: None,
+            deadband: Deadband::new(DeadbandConfig::default()), derived: Derived::new(&derived).unwrap() };
+
+        let (resolver, db_receiver) = resolver(stage);
+        let measurement = |unit: &str, value: Value| Measurement { unit: unit.to_string(), value, timestamp: None };
+
+        resolver.resolve(1, "meter".to_string(), json!(1), None, vec![measurement("voltage", json!(230)), measurement("current", json!(2))], Utc::now());
+        resolver.resolve(1, "meter".to_string(), json!(2), None, vec![measurement("voltage", json!(230)), measurement("current", json!(10))], Utc::now());
+        let stored = |message: Command| match message {
+            Command::Store(_, records, _) => records.into_iter().map(|record| (record.unit, record.value)).collect::<Vec<(String, Value)>>(),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(stored(db_receiver.recv().unwrap()).contains(&("power".to_string(), json!(460.0))));
+        // 2300 W is rejected, voltage and current are stored
+        assert_eq!(stored(db_receiver.recv().unwrap()).len(), 2);
+    }
+}
@@ -0,0 +1,201 @@
+//** Nested payloads */
+//** Nested maps are flattened into unit paths joined with a separator: {"env": {"temperature": 23}} gives env.temperature. */
+//** Devices with extraction rules get their units from JSONPath-style paths instead, e.g. $.readings[0].value */
+
+use std::collections::BTreeMap;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arrays {
+    /// An array is one value of the unit
+    Keep,
+    /// Every item is a unit with its index, e.g. readings.0
+    Index,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FlattenConfig {
+    /// Nested maps are stored as one JSON value if false (the default, as before flattening was added)
+    pub enabled: bool,
+    pub separator: String,
+    pub arrays: Arrays,
+    /// Unit paths by device UID, e.g. {"meter-1": {"temperature": "$.env.temp", "power": "$.phases[0].power"}};
+    /// only units of the rules are taken from payloads of these devices
+    pub rules: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Default for FlattenConfig {
+    fn default() -> Self {
+        FlattenConfig {
+            enabled: false,
+            separator: ".".to_string(),
+            arrays: Arrays::Keep,
+            rules: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    /// Negative indexes count from the end
+    Index(i64),
+}
+
+/// Parse $.a.b[0]['c d'] paths, the leading $ is optional
+fn parse(path: &str) -> Result<Vec<Segment>, String> {
+    let chars: Vec<char> = path.trim().chars().collect();
+    let mut position = if chars.first() == Some(&'$') { 1 } else { 0 };
+    let mut segments = Vec::new();
+    let invalid = || format!("invalid path {}", path);
+
+    while position < chars.len() {
+        match chars[position] {
+            '.' => {
+                let start = position + 1;
+                position = start;
+                while position < chars.len() && chars[position] != '.' && chars[position] != '[' {
+                    position += 1;
+                }
+                if position == start {
+                    return Err(invalid());
+                }
+                segments.push(Segment::Key(chars[start..position].iter().collect()));
+            },
+            '[' => {
+                let end = chars[position..].iter().position(|c| *c == ']').map(|offset| position + offset).ok_or_else(invalid)?;
+                let inner: String = chars[position + 1..end].iter().collect();
+                let quoted = inner.len() >= 2 && ((inner.starts_with('\'') && inner.ends_with('\'')) || (inner.starts_with('"') && inner.ends_with('"')));
+                if quoted {
+                    segments.push(Segment::Key(inner[1..inner.len() - 1].to_string()));
+                } else {
+                    segments.push(Segment::Index(inner.trim().parse().map_err(|_| invalid())?));
+                }
+                position = end + 1;
+            },
+            // a path could start with a key: env.temperature
+            _ if segments.is_empty() && position == 0 => {
+                let start = position;
+                while position < chars.len() && chars[position] != '.' && chars[position] != '[' {
+                    position += 1;
+                }
+                segments.push(Segment::Key(chars[start..position].iter().collect()));
+            },
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(segments)
+}
+
+/// Parsed JSONPath-style path of a unit
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(Vec<Segment>);
+
+impl Path {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        parse(path).map(Path)
+    }
+
+    /// Value at the path, None if there is no such value
+    pub fn select<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        let mut current = value;
+        for segment in &self.0 {
+            current = match (segment, current) {
+                (Segment::Key(key), Value::Object(map)) => map.get(key)?,
+                (Segment::Index(index), Value::Array(items)) => {
+                    let index = if *index < 0 { items.len() as i64 + index } else { *index };
+                    if index < 0 { return None } else { items.get(index as usize)? }
+                },
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+}
+
+/// Value at the path, None if there is no such value
+pub fn select<'a>(value: &'a Value, path: &str) -> Result<Option<&'a Value>, String> {
+    Path::parse(path).map(|path| path.select(value))
+}
+
+/// Parsed unit paths by device UID, an invalid path is an error
+pub fn compile(rules: &BTreeMap<String, BTreeMap<String, String>>) -> Result<BTreeMap<String, Vec<(String, Path)>>, String> {
+    rules.iter().map(|(uid, paths)| {
+        paths.iter()
+            .map(|(unit, path)| Path::parse(path).map(|path| (unit.clone(), path)).map_err(|error| format!("{} of device {}: {}", unit, uid, error)))
+            .collect::<Result<Vec<(String, Path)>, String>>()
+            .map(|paths| (uid.clone(), paths))
+    }).collect()
+}
+
+fn flatten_into(prefix: String, value: Value, config: &FlattenConfig, result: &mut Vec<(String, Value)>) {
+    let join = |key: &str| if prefix.is_empty() { key.to_string() } else { format!("{}{}{}", prefix, config.separator, key) };
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten_into(join(&key), value, config, result);
+            }
+        },
+        Value::Array(items) if config.arrays == Arrays::Index => {
+            for (index, value) in items.into_iter().enumerate() {
+                flatten_into(join(&index.to_string()), value, config, result);
+            }
+        },
+        value => result.push((prefix, value)),
+    }
+}
+
+/// Units and values of a map payload
+pub fn flatten(map: Map<String, Value>, config: &FlattenConfig) -> Vec<(String, Value)> {
+    if !config.enabled {
+        return map.into_iter().collect();
+    }
+    let mut result = Vec::new();
+    for (key, value) in map {
+        flatten_into(key, value, config, &mut result);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_flatten() {
+        let payload = json!({ "env": { "temperature": 23, "humidity": 40, "inner": { "co2": 400 } }, "readings": [1, 2], "on": true });
+        let map = payload.as_object().unwrap().clone();
+        assert_eq!(flatten(map.clone(), &FlattenConfig::default()).len(), 3);
+        let enabled = FlattenConfig { enabled: true, ..FlattenConfig::default() };
+        assert_eq!(flatten(map.clone(), &enabled), vec![
+            ("env.humidity".to_string(), json!(40)), ("env.inner.co2".to_string(), json!(400)),
+            ("env.temperature".to_string(), json!(23)), ("on".to_string(), json!(true)), ("readings".to_string(), json!([1, 2])),
+        ]);
+        let config = FlattenConfig { separator: "/".to_string(), arrays: Arrays::Index, ..enabled };
+        let flat = flatten(map, &config);
+        assert!(flat.contains(&("env/inner/co2".to_string(), json!(400))));
+        assert!(flat.contains(&("readings/1".to_string(), json!(2))));
+    }
+
+    #[test]
+    fn test_select_path() {
+        let payload = json!({ "phases": [{ "power": 10 }, { "power": 12 }], "env": { "air temp": 21.5 } });
+        assert_eq!(select(&payload, "$.phases[1].power").unwrap(), Some(&json!(12)));
+        assert_eq!(select(&payload, "$.phases[-2].power").unwrap(), Some(&json!(10)));
+        assert_eq!(select(&payload, "$.env['air temp']").unwrap(), Some(&json!(21.5)));
+        assert_eq!(select(&payload, "env").unwrap(), Some(&json!({ "air temp": 21.5 })));
+        assert_eq!(select(&payload, "$.phases[5]").unwrap(), None);
+        assert!(select(&payload, "$.phases[x]").is_err());
+        assert!(select(&payload, "$..env").is_err());
+
+        let mut rules = BTreeMap::new();
+        rules.insert("meter".to_string(), vec![("power".to_string(), "$.phases[0].power".to_string())].into_iter().collect());
+        assert_eq!(compile(&rules).unwrap()["meter"][0].1.select(&payload), Some(&json!(10)));
+        rules.insert("broken".to_string(), vec![("power".to_string(), "$.phases[".to_string())].into_iter().collect());
+        assert!(compile(&rules).is_err());
+    }
+}
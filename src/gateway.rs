@@ -0,0 +1,84 @@
+//** Gateways */
+//** A gateway publishes readings of many devices in one message to gateways/<uid>/data[/<format>]: */
+//** {"devices": {"<uid>": {...}}} or a list of maps with a uid field. The message is split into per device messages */
+//** for the devices the gateway is allowed to speak for */
+
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GatewayConfig {
+    /// UIDs of devices behind the gateway, a trailing * matches a prefix (e.g. lora-*)
+    pub devices: Vec<String>,
+    /// Key of the device UID in list payloads
+    pub uid_key: String,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        GatewayConfig {
+            devices: Vec::new(),
+            uid_key: "uid".to_string(),
+        }
+    }
+}
+
+impl GatewayConfig {
+    pub fn allows(&self, uid: &str) -> bool {
+        self.devices.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => uid.starts_with(prefix),
+            None => pattern == uid,
+        })
+    }
+
+    /// Device UIDs and their messages
+    pub fn split(&self, value: Value) -> Result<Vec<(String, Value)>, String> {
+        let devices = match value {
+            Value::Object(mut map) => map.remove("devices").ok_or_else(|| "no devices in the gateway message".to_string())?,
+            list @ Value::Array(_) => list,
+            _ => return Err("the gateway message should be a map or a list".to_string()),
+        };
+        match devices {
+            Value::Object(map) => Ok(map.into_iter().collect()),
+            Value::Array(items) => items.into_iter().map(|item| match item {
+                Value::Object(mut map) => match map.remove(&self.uid_key) {
+                    Some(Value::String(uid)) => Ok((uid, Value::Object(map))),
+                    _ => Err(format!("a device of the gateway message has no {}", self.uid_key)),
+                },
+                _ => Err("devices of the gateway message should be maps".to_string()),
+            }).collect(),
+            _ => Err("devices of the gateway message should be a map or a list".to_string()),
+        }
+    }
+}
+
+/// Gateway UID of gateways/<uid>/data[/<format>] topics
+pub fn gateway_uid(topic: &str) -> Option<&str> {
+    let levels: Vec<&str> = topic.split('/').collect();
+    match levels.as_slice() {
+        ["gateways", uid, "data"] | ["gateways", uid, "data", _] => Some(uid),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_split_gateway_message() {
+        let config = GatewayConfig { devices: vec!["lora-*".to_string(), "ble-1".to_string()], ..GatewayConfig::default() };
+        assert!(config.allows("lora-17") && config.allows("ble-1") && !config.allows("ble-2"));
+
+        let by_uid = config.split(json!({ "devices": { "lora-1": { "temp": 20 }, "ble-1": { "temp": 21 } } })).unwrap();
+        assert_eq!(by_uid, vec![("ble-1".to_string(), json!({ "temp": 21 })), ("lora-1".to_string(), json!({ "temp": 20 }))]);
+        let list = config.split(json!([{ "uid": "lora-1", "temp": 20, "msg_id": 7 }])).unwrap();
+        assert_eq!(list, vec![("lora-1".to_string(), json!({ "temp": 20, "msg_id": 7 }))]);
+        assert!(config.split(json!({ "devices": [{ "temp": 20 }] })).is_err());
+
+        assert_eq!(gateway_uid("gateways/gw-1/data/cbor"), Some("gw-1"));
+        assert_eq!(gateway_uid("devices/gw-1/data"), None);
+    }
+}
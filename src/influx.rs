@@ -0,0 +1,205 @@
+//** InfluxDB sink */
+//** Records are written in line protocol: <measurement>,uid=<uid>,unit=<unit>,device_id=<id> value=<value> <ns timestamp> */
+//** to an HTTP write endpoint (InfluxDB 1.x /write or 2.x /api/v2/write) or appended to a file. */
+//** On a partial write the endpoint stores the valid lines, the rejected ones are logged */
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+use log::error;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::metrics;
+use super::sink::{self, BatchPolicy, Record, Sink, SinkError};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct InfluxConfig {
+    /// Write endpoint with the query, e.g. http://localhost:8086/api/v2/write?org=fennec&bucket=records&precision=ns
+    pub url: Option<String>,
+    /// File to append lines to, used if no url is set
+    pub file: Option<String>,
+    /// Sent as `Authorization: Token <token>`
+    pub token: Option<String>,
+    pub measurement: String,
+    /// Seconds to wait for the endpoint
+    pub timeout: u64,
+    pub batch: BatchPolicy,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        InfluxConfig {
+            url: None,
+            file: None,
+            token: None,
+            measurement: "records".to_string(),
+            timeout: 10,
+            batch: BatchPolicy::default(),
+        }
+    }
+}
+
+/// Escape commas, spaces and, in tags, equal signs
+fn escape(text: &str, equals: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == ',' || c == ' ' || (equals && c == '=') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+fn string_field(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Numbers are written as floats, so integer and float measurements of a unit do not conflict
+fn field(value: &Value) -> String {
+    match value {
+        Value::Number(number) => number.to_string(),
+        Value::Bool(flag) => flag.to_string(),
+        Value::String(text) => string_field(text),
+        other => string_field(&other.to_string()),
+    }
+}
+
+pub fn line(measurement: &str, record: &Record) -> String {
+    format!("{},uid={},unit={},device_id={} value={} {}",
+        escape(measurement, false), escape(&record.uid, true), escape(&record.unit, true), record.device_id,
+        field(&record.value), record.timestamp.timestamp_nanos())
+}
+
+/// Lines rejected by a partial write, None if the response is not a partial write.
+/// The error is in "message" (2.x) or "error" (1.x): partial write ... unable to parse '<line>': <reason>
+fn rejected_lines(text: &str) -> Option<Vec<String>> {
+    let json: Option<Value> = serde_json::from_str(text).ok();
+    let message = json.as_ref()
+        .and_then(|json| json.get("message").or_else(|| json.get("error")))
+        .and_then(Value::as_str)
+        .unwrap_or(text);
+    if !message.contains("partial write") {
+        return None;
+    }
+    Some(message.split("unable to parse '").skip(1)
+        .filter_map(|rest| rest.find("': ").map(|end| rest[..end].to_string()))
+        .collect())
+}
+
+pub struct InfluxSink {
+    config: InfluxConfig,
+}
+
+impl InfluxSink {
+    pub fn new(config: InfluxConfig) -> Self {
+        InfluxSink { config }
+    }
+
+    fn post(&self, url: &str, body: String) -> Result<(), SinkError> {
+        let mut headers = Vec::new();
+        if let Some(token) = &self.config.token {
+            headers.push(("Authorization".to_string(), format!("Token {}", token)));
+        }
+        let (status, text) = sink::http_post_response(url, "text/plain; charset=utf-8", &headers, body.into_bytes(),
+            Duration::from_secs(self.config.timeout))?;
+        // valid lines of a partial write are stored, a retry would not store the rejected ones
+        if status == 400 {
+            if let Some(lines) = rejected_lines(&text) {
+                error!("InfluxDB rejected {} lines of a partial write: {}", lines.len(), text);
+                for line in &lines {
+                    error!("InfluxDB rejected line: {}", line);
+                }
+                metrics::add("influx_rejected_lines_total", lines.len() as u64);
+                return Ok(());
+            }
+        }
+        sink::status_result(status, text)
+    }
+
+    fn append(&self, path: &str, body: String) -> Result<(), SinkError> {
+        OpenOptions::new().create(true).append(true).open(path)
+            .and_then(|mut file| file.write_all(body.as_bytes()))
+            .map_err(|error| SinkError::Retry(error.to_string()))
+    }
+}
+
+impl Sink for InfluxSink {
+    fn name(&self) -> &'static str {
+        "influx"
+    }
+
+    fn write(&mut self, records: &[Record]) -> Result<(), SinkError> {
+        let mut body = String::new();
+        for record in records {
+            body.push_str(&line(&self.config.measurement, record));
+            body.push('\n');
+        }
+        match (&self.config.url, &self.config.file) {
+            (Some(url), _) => self.post(url, body),
+            (None, Some(path)) => self.append(path, body),
+            (None, None) => Err(SinkError::Drop("no url or file is configured".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+    use chrono::prelude::*;
+    use serde_json::json;
+
+    fn record(unit: &str, value: Value) -> Record {
+        Record { device_id: 7, uid: "dev 1".to_string(), unit_id: 3, unit: unit.to_string(), value,
+            timestamp: Utc.timestamp(1_600_000_000, 5) }
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        assert_eq!(line("records", &record("temp,c", json!(21.5))),
+            "records,uid=dev\\ 1,unit=temp\\,c,device_id=7 value=21.5 1600000000000000005");
+        assert_eq!(line("records", &record("state", json!("on \"1\""))),
+            "records,uid=dev\\ 1,unit=state,device_id=7 value=\"on \\\"1\\\"\" 1600000000000000005");
+        assert_eq!(line("records", &record("door", json!(true))),
+            "records,uid=dev\\ 1,unit=door,device_id=7 value=true 1600000000000000005");
+    }
+
+    #[test]
+    fn test_rejected_lines() {
+        let v2 = r#"{"code":"invalid","message":"partial write error (1 written): unable to parse 'records,uid=a value=\"x': missing field"}"#;
+        assert_eq!(rejected_lines(v2), Some(vec!["records,uid=a value=\"x".to_string()]));
+        let v1 = r#"{"error":"partial write: unable to parse 'bad line': invalid field format dropped=1"}"#;
+        assert_eq!(rejected_lines(v1), Some(vec!["bad line".to_string()]));
+        assert_eq!(rejected_lines(r#"{"code":"invalid","message":"invalid bucket"}"#), None);
+    }
+
+    #[test]
+    fn test_http_write() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v2/write?bucket=test", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            // read until the whole line is received
+            while !String::from_utf8_lossy(&request).contains("1600000000000000005\n") {
+                let size = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..size]);
+            }
+            stream.write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+
+        let mut sink = InfluxSink::new(InfluxConfig { url: Some(url), token: Some("secret".to_string()), ..InfluxConfig::default() });
+        assert!(sink.write(&[record("temp", json!(1))]).is_ok());
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v2/write?bucket=test"));
+        assert!(request.to_lowercase().contains("authorization: token secret"));
+        assert!(request.contains("records,uid=dev\\ 1,unit=temp,device_id=7 value=1 1600000000000000005"));
+    }
+}
@@ -0,0 +1,42 @@
+//** JSON Lines sink */
+//** Every record is appended to a file as a JSON object: device_id, uid, unit_id, unit, value and timestamp */
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use serde::Deserialize;
+
+use super::sink::{BatchPolicy, Record, Sink, SinkError};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct JsonlConfig {
+    pub file: String,
+    pub batch: BatchPolicy,
+}
+
+pub struct JsonlSink {
+    config: JsonlConfig,
+}
+
+impl JsonlSink {
+    pub fn new(config: JsonlConfig) -> Self {
+        JsonlSink { config }
+    }
+}
+
+impl Sink for JsonlSink {
+    fn name(&self) -> &'static str {
+        "jsonl"
+    }
+
+    fn write(&mut self, records: &[Record]) -> Result<(), SinkError> {
+        let mut body = String::new();
+        for record in records {
+            body.push_str(&record.to_json().to_string());
+            body.push('\n');
+        }
+        OpenOptions::new().create(true).append(true).open(&self.config.file)
+            .and_then(|mut file| file.write_all(body.as_bytes()))
+            .map_err(|error| SinkError::Retry(error.to_string()))
+    }
+}
@@ -1,3 +1,27 @@
 pub mod feeder;
 pub mod phoenix;
-pub mod matrix_storage;
\ No newline at end of file
+pub mod matrix_storage;
+pub mod config;
+pub mod commands;
+pub mod live;
+pub mod backoff;
+pub mod metrics;
+pub mod db;
+pub mod resync;
+pub mod migrations;
+pub mod retention;
+pub mod sink;
+pub mod influx;
+pub mod jsonl;
+pub mod republish;
+pub mod webhook;
+pub mod payload;
+pub mod senml;
+pub mod schema;
+pub mod sparkplug;
+pub mod flatten;
+pub mod gateway;
+pub mod validation;
+pub mod dedup;
+pub mod deadband;
+pub mod derived;
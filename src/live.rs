@@ -0,0 +1,98 @@
+//** Live measurements pusher */
+//** Pushes resolved measurements to Phoenix `device:<id>` topics, so dashboards are updated without polling DB. */
+//** Records are coalesced per device and unit: only the latest values are pushed, at most once per interval */
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use log::{warn, error};
+use crossbeam::channel;
+use serde_json::{Value, json};
+use chrono::prelude::*;
+use websocket::OwnedMessage;
+
+use super::feeder::Command;
+use super::phoenix;
+
+pub const NEW_RECORD_EVENT: &str = "new_record";
+
+pub fn device_topic(device_id: usize) -> String {
+    format!("device:{}", device_id)
+}
+
+/// Latest values of a device: unit_id -> (unit name, value, time)
+type DeviceRecords = BTreeMap<usize, (String, String, DateTime<Utc>)>;
+
+fn push_message(device_id: usize, records: DeviceRecords) -> OwnedMessage {
+    let records: Vec<Value> = records.into_iter().map(|(unit_id, (unit, value, time))| {
+        // values are stored as JSON strings, push them typed
+        let value = serde_json::from_str::<Value>(&value).unwrap_or(Value::String(value));
+        json!({ "unit_id": unit_id, "unit": unit, "value": value, "inserted_at": time.to_rfc3339() })
+    }).collect();
+    phoenix::push_message(&device_topic(device_id), NEW_RECORD_EVENT, json!({ "device_id": device_id, "records": records }))
+}
+
+/// A thread which coalesces live records and pushes them to Phoenix every `interval`
+pub fn pusher(live_receiver: channel::Receiver<Command>, phoenix_sender: channel::Sender<OwnedMessage>, interval: Duration) {
+    use Command::*;
+    let mut pending: BTreeMap<usize, DeviceRecords> = BTreeMap::new();
+    let mut last_push = Instant::now();
+
+    loop {
+        let timeout = interval.checked_sub(last_push.elapsed()).unwrap_or_else(|| Duration::from_millis(0));
+        match live_receiver.recv_timeout(timeout) {
+            Ok(Live(device_id, records)) => {
+                let now = Utc::now();
+                let device_records = pending.entry(device_id).or_default();
+                for (unit_id, unit, value) in records {
+                    device_records.insert(unit_id, (unit, value, now));
+                }
+            },
+            Ok(message) => {
+                warn!("Live Pusher thread: unimplemented message: {:?}", message);
+            },
+            Err(channel::RecvTimeoutError::Timeout) => {},
+            Err(channel::RecvTimeoutError::Disconnected) => {
+                return;
+            }
+        }
+
+        if last_push.elapsed() >= interval {
+            for (device_id, records) in std::mem::take(&mut pending) {
+                if let Err(error) = phoenix_sender.send(push_message(device_id, records)) {
+                    error!("Live Pusher thread error: {}", error);
+                }
+            }
+            last_push = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce_records() {
+        let (live_sender, live_receiver) = channel::unbounded();
+        let (phoenix_sender, phoenix_receiver) = channel::unbounded();
+
+        live_sender.send(Command::Live(7, vec![(1, "temperature".to_string(), "21".to_string())])).unwrap();
+        live_sender.send(Command::Live(7, vec![(1, "temperature".to_string(), "23".to_string())])).unwrap();
+        let pusher_thread = std::thread::spawn(move || {
+            pusher(live_receiver, phoenix_sender, Duration::from_millis(200));
+        });
+
+        match phoenix_receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+            OwnedMessage::Text(text) => {
+                let message: Value = serde_json::from_str(&text).unwrap();
+                assert_eq!(message[2], "device:7");
+                assert_eq!(message[3], NEW_RECORD_EVENT);
+                assert_eq!(message[4]["records"].as_array().unwrap().len(), 1);
+                assert_eq!(message[4]["records"][0]["value"], 23);
+            },
+            message => panic!("unexpected message {:?}", message),
+        }
+        drop(live_sender);
+        pusher_thread.join().unwrap();
+    }
+}
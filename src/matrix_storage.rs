@@ -36,6 +36,20 @@ impl<T: Copy + Clone> MatrixStorage<T> {
         None
     }
 
+    /// Remove value, the matrix keeps its size
+    pub fn remove(&mut self, col: usize, row: usize) {
+        if row < self.matrix.len() && col < self.matrix[row].len() {
+            self.matrix[row][col] = None;
+        }
+    }
+
+    /// Iterate over (col, row, value) of all set values
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, T)> + '_ {
+        self.matrix.iter().enumerate().flat_map(|(row, row_vector)| {
+            row_vector.iter().enumerate().filter_map(move |(col, value)| value.map(|value| (col, row, value)))
+        })
+    }
+
     /// Return row vector 
     /// If 'row' exceeds length of array None returned
     pub fn row(&self, row: usize) -> Option<&Vec<Option<T>>> {
@@ -73,4 +87,15 @@ mod tests {
 
         assert_eq!(mstorage.get(1, 2), None);
     }
+
+    #[test]
+    fn test_remove_and_iterate() {
+        let mut mstorage = MatrixStorage::new();
+        mstorage.add(2, 3, true);
+        mstorage.add(0, 1, true);
+        mstorage.remove(2, 3);
+
+        assert_eq!(mstorage.get(2, 3), None);
+        assert_eq!(mstorage.iter().collect::<Vec<_>>(), vec![(0, 1, true)]);
+    }
 }
\ No newline at end of file
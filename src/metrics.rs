@@ -0,0 +1,100 @@
+//** Metrics */
+//** Named counters and gauges shared by all threads, exposed in Prometheus text format. */
+//** A name could carry labels: reconnect_attempts_total{connection="mqtt"} */
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::time::Duration;
+use log::{info, error};
+
+pub const PREFIX: &str = "ffeeder_";
+
+static METRICS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+pub fn add(name: &str, value: u64) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        *metrics.entry(name.to_string()).or_insert(0) += value;
+    }
+}
+
+pub fn increment(name: &str) {
+    add(name, 1);
+}
+
+pub fn set(name: &str, value: u64) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.insert(name.to_string(), value);
+    }
+}
+
+pub fn get(name: &str) -> u64 {
+    METRICS.lock().ok().and_then(|metrics| metrics.get(name).copied()).unwrap_or(0)
+}
+
+/// Render all metrics in Prometheus text format
+pub fn render() -> String {
+    let mut result = String::new();
+    if let Ok(metrics) = METRICS.lock() {
+        for (name, value) in metrics.iter() {
+            result.push_str(&format!("{}{} {}\n", PREFIX, name, value));
+        }
+    }
+    result
+}
+
+/// Max time to read a request or write a response, a stalled client does not block the server
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A thread which answers any HTTP request with metrics
+pub fn serve(address: &str) {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Cannot start metrics server on {} due to error: {}", address, error);
+            return;
+        }
+    };
+    info!("Metrics are available on http://{}/metrics", address);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                if let Err(error) = stream.set_read_timeout(Some(TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(TIMEOUT))) {
+                    error!("Metrics server error: {}", error);
+                    continue;
+                }
+                // the request itself is not important, read it to not reset the connection
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer);
+                let body = render();
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(), body);
+                if let Err(error) = stream.write_all(response.as_bytes()) {
+                    error!("Metrics server error: {}", error);
+                }
+            },
+            Err(error) => {
+                error!("Metrics server error: {}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_are_rendered() {
+        increment("test_counter{kind=\"a\"}");
+        add("test_counter{kind=\"a\"}", 2);
+        set("test_gauge", 5);
+
+        assert_eq!(get("test_counter{kind=\"a\"}"), 3);
+        let text = render();
+        assert!(text.contains("ffeeder_test_counter{kind=\"a\"} 3\n"));
+        assert!(text.contains("ffeeder_test_gauge 5\n"));
+    }
+}
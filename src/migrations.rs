@@ -0,0 +1,161 @@
+//** Schema migrations */
+//** Versioned SQL migrations are embedded into the binary and applied by `ffeeder migrate`. */
+//** Applied versions are kept in ffeeder_migrations table, schema_migrations belongs to the Phoenix app */
+
+use log::{info, warn};
+use mysql::prelude::*;
+use mysql::PooledConn;
+
+use super::db;
+
+pub const MIGRATIONS_TABLE: &str = "ffeeder_migrations";
+
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "create_devices", sql: include_str!("../migrations/0001_create_devices.sql") },
+    Migration { version: 2, name: "create_units", sql: include_str!("../migrations/0002_create_units.sql") },
+    Migration { version: 3, name: "create_devices_units", sql: include_str!("../migrations/0003_create_devices_units.sql") },
+    Migration { version: 4, name: "create_records", sql: include_str!("../migrations/0004_create_records.sql") },
+    Migration { version: 5, name: "index_records", sql: include_str!("../migrations/0005_index_records.sql") },
+    Migration { version: 6, name: "unique_devices_units", sql: include_str!("../migrations/0006_unique_devices_units.sql") },
+    Migration { version: 7, name: "unique_units_name", sql: include_str!("../migrations/0007_unique_units_name.sql") },
+    Migration { version: 8, name: "create_records_aggregates", sql: include_str!("../migrations/0008_create_records_aggregates.sql") },
+    Migration { version: 9, name: "add_devices_units_last_seen_at", sql: include_str!("../migrations/0009_add_devices_units_last_seen_at.sql") },
+    Migration { version: 10, name: "add_records_aggregates_watermark", sql: include_str!("../migrations/0010_add_records_aggregates_watermark.sql") },
+];
+
+/// Tables and columns the feeder works with
+const REQUIRED_COLUMNS: &[(&str, &[&str])] = &[
+    ("devices", &["id", "uid", "active", "deleted_at"]),
+    ("units", &["id", "name", "inserted_at", "updated_at"]),
+    ("devices_units", &["device_id", "unit_id", "inserted_at", "updated_at"]),
+    ("records", &["device_id", "unit_id", "value", "inserted_at", "updated_at"]),
+];
+
+/// Columns required by the deadband, written by Seen commands
+pub const DEADBAND_COLUMNS: &[(&str, &[&str])] = &[
+    ("devices_units", &["last_seen_at"]),
+];
+
+/// Columns required by the retention, written when records are rolled up
+pub const RETENTION_COLUMNS: &[(&str, &[&str])] = &[
+    ("records_hourly", &["last_record_id", "last_value_at"]),
+    ("records_daily", &["last_record_id", "last_value_at"]),
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.iter().map(|migration| migration.version).max().unwrap_or(0)
+}
+
+/// Split SQL to statements, comments and empty statements are skipped
+pub fn statements(sql: &str) -> Vec<String> {
+    sql.split(';')
+        .map(|statement| statement.lines()
+            .filter(|line| !line.trim_start().starts_with("--"))
+            .collect::<Vec<&str>>()
+            .join("\n"))
+        .map(|statement| statement.trim().to_string())
+        .filter(|statement| !statement.is_empty())
+        .collect()
+}
+
+/// Tables could be already created by the Phoenix app: existing tables and indexes are not errors
+fn already_exists(error: &mysql::Error) -> bool {
+    // table exists, duplicate column name, duplicate key name
+    matches!(error, mysql::Error::MySqlError(error) if error.code == 1050 || error.code == 1060 || error.code == 1061)
+}
+
+/// Version of the last applied migration, None if migrations were never run
+pub fn applied_version(conn: &mut PooledConn) -> mysql::Result<Option<u32>> {
+    let tables: Option<u32> = conn.exec_first("SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = ?",
+        (MIGRATIONS_TABLE,))?;
+    if tables.unwrap_or(0) == 0 {
+        return Ok(None);
+    }
+    let version: Option<Option<u32>> = conn.query_first(format!("SELECT MAX(version) FROM {}", MIGRATIONS_TABLE))?;
+    Ok(version.flatten())
+}
+
+/// Apply pending migrations in order, return the version of the schema
+pub fn migrate(conn: &mut PooledConn) -> mysql::Result<u32> {
+    conn.query_drop(format!("CREATE TABLE IF NOT EXISTS {} (
+        version INT UNSIGNED NOT NULL PRIMARY KEY,
+        name VARCHAR(255) NOT NULL,
+        applied_at DATETIME NOT NULL
+    )", MIGRATIONS_TABLE))?;
+    let current = applied_version(conn)?.unwrap_or(0);
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        info!("Apply migration {} {}", migration.version, migration.name);
+        for statement in statements(migration.sql) {
+            match conn.query_drop(&statement) {
+                Ok(()) => {},
+                Err(error) if already_exists(&error) => {
+                    warn!("Migration {}: {}, skip the statement", migration.version, error);
+                },
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+        conn.exec_drop(format!("INSERT INTO {} (version, name, applied_at) VALUES (?, ?, ?)", MIGRATIONS_TABLE),
+            (migration.version, migration.name, db::timestamp()))?;
+    }
+    Ok(latest_version().max(current))
+}
+
+/// Check that DB has all tables and columns the feeder needs
+/// Check the schema has the required columns and the columns of enabled features
+pub fn check(conn: &mut PooledConn, features: &[(&str, &[&str])]) -> Result<(), String> {
+    let mut missing = Vec::new();
+    for (table, columns) in REQUIRED_COLUMNS.iter().chain(features) {
+        let existing: Vec<String> = conn.exec("SELECT column_name FROM information_schema.columns WHERE table_schema = DATABASE() AND table_name = ?",
+            (table,)).map_err(|error| error.to_string())?;
+        for column in columns.iter() {
+            if !existing.iter().any(|name| name.eq_ignore_ascii_case(column)) {
+                missing.push(format!("{}.{}", table, column));
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!("missing columns: {}, run `ffeeder migrate`", missing.join(", ")));
+    }
+
+    match applied_version(conn).map_err(|error| error.to_string())? {
+        None => warn!("Database schema is not managed by ffeeder migrations"),
+        Some(version) if version < latest_version() => {
+            warn!("Database schema version {} is older than {}, run `ffeeder migrate`", version, latest_version());
+        },
+        Some(version) if version > latest_version() => {
+            warn!("Database schema version {} is newer than supported {}", version, latest_version());
+        },
+        Some(version) => info!("Database schema version {}", version),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        let versions: Vec<u32> = MIGRATIONS.iter().map(|migration| migration.version).collect();
+        let expected: Vec<u32> = (1..=MIGRATIONS.len() as u32).collect();
+        assert_eq!(versions, expected);
+        assert_eq!(latest_version(), MIGRATIONS.len() as u32);
+    }
+
+    #[test]
+    fn test_statements_skip_comments() {
+        let sql = "-- comment\nCREATE TABLE a (id INT);\n\nCREATE INDEX b ON a (id);\n";
+        assert_eq!(statements(sql), vec!["CREATE TABLE a (id INT)", "CREATE INDEX b ON a (id)"]);
+        assert_eq!(statements(MIGRATIONS[5].sql).len(), 1);
+    }
+}
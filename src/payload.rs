@@ -0,0 +1,208 @@
+//** Payload decoding */
+//** Device payloads are decoded to the same JSON map of unit names and values whatever the encoding is: */
+//** JSON, CBOR, MessagePack or protobuf. The format is taken from MQTT v5 content type, topic suffix or per device config. */
+//** A top level array is a SenML pack of records */
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use chrono::prelude::*;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+
+use super::feeder::MESSAGE_ID_KEY;
+use super::flatten::{self, FlattenConfig, Path};
+use super::schema::Registry;
+use super::senml::SenmlConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Cbor,
+    Msgpack,
+    Protobuf,
+}
+
+impl Format {
+    /// Format by the last topic level, e.g. devices/<uid>/data/cbor
+    pub fn from_topic(topic: &str) -> Option<Self> {
+        match topic.rsplit('/').next() {
+            Some("json") => Some(Format::Json),
+            Some("cbor") => Some(Format::Cbor),
+            Some("msgpack") => Some(Format::Msgpack),
+            Some("protobuf") => Some(Format::Protobuf),
+            _ => None,
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        // parameters like charset are ignored
+        match content_type.split(';').next().unwrap_or("").trim() {
+            "application/json" | "application/senml+json" => Some(Format::Json),
+            "application/cbor" | "application/senml+cbor" => Some(Format::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::Msgpack),
+            "application/protobuf" | "application/x-protobuf" | "application/vnd.google.protobuf" => Some(Format::Protobuf),
+            _ => None,
+        }
+    }
+}
+
+/// Content type of the message goes first, then the topic suffix, then the device format; JSON by default
+pub fn select(topic: &str, content_type: Option<&str>, device_format: Option<Format>) -> Format {
+    content_type.and_then(Format::from_content_type)
+        .or_else(|| Format::from_topic(topic))
+        .or(device_format)
+        .unwrap_or(Format::Json)
+}
+
+fn float(value: f64) -> Value {
+    // NaN and infinity have no JSON representation, they are rejected as invalid values
+    Number::from_f64(value).map(Value::Number).unwrap_or(Value::Null)
+}
+
+/// Map keys are strings in JSON, other keys are written as JSON
+fn key(value: Value) -> String {
+    match value {
+        Value::String(text) => text,
+        other => other.to_string(),
+    }
+}
+
+fn from_cbor(value: serde_cbor::Value) -> Value {
+    use serde_cbor::Value as Cbor;
+    match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(flag) => Value::Bool(flag),
+        Cbor::Integer(number) => {
+            if let Ok(number) = i64::try_from(number) {
+                Value::from(number)
+            } else if let Ok(number) = u64::try_from(number) {
+                Value::from(number)
+            } else {
+                float(number as f64)
+            }
+        },
+        Cbor::Float(number) => float(number),
+        Cbor::Bytes(bytes) => Value::String(hex::encode(bytes)),
+        Cbor::Text(text) => Value::String(text),
+        Cbor::Array(items) => Value::Array(items.into_iter().map(from_cbor).collect()),
+        Cbor::Map(entries) => Value::Object(entries.into_iter().map(|(k, v)| (key(from_cbor(k)), from_cbor(v))).collect::<Map<String, Value>>()),
+        // tags like epoch time keep the tagged value
+        Cbor::Tag(_, value) => from_cbor(*value),
+        _ => Value::Null,
+    }
+}
+
+fn from_msgpack(value: rmpv::Value) -> Value {
+    use rmpv::Value as Msgpack;
+    match value {
+        Msgpack::Nil => Value::Null,
+        Msgpack::Boolean(flag) => Value::Bool(flag),
+        Msgpack::Integer(number) => {
+            if let Some(number) = number.as_i64() {
+                Value::from(number)
+            } else {
+                number.as_u64().map(Value::from).unwrap_or(Value::Null)
+            }
+        },
+        Msgpack::F32(number) => float(number as f64),
+        Msgpack::F64(number) => float(number),
+        Msgpack::String(text) => text.into_str().map(Value::String).unwrap_or(Value::Null),
+        Msgpack::Binary(bytes) => Value::String(hex::encode(bytes)),
+        Msgpack::Array(items) => Value::Array(items.into_iter().map(from_msgpack).collect()),
+        Msgpack::Map(entries) => Value::Object(entries.into_iter().map(|(k, v)| (key(from_msgpack(k)), from_msgpack(v))).collect::<Map<String, Value>>()),
+        Msgpack::Ext(_, bytes) => Value::String(hex::encode(bytes)),
+    }
+}
+
+/// Decode a self-describing payload, protobuf payloads are decoded by the Decoder
+pub fn decode(format: Format, payload: &[u8]) -> Result<Value, String> {
+    match format {
+        Format::Json => serde_json::from_slice(payload).map_err(|error| error.to_string()),
+        Format::Cbor => serde_cbor::from_slice(payload).map(from_cbor).map_err(|error| error.to_string()),
+        Format::Msgpack => rmpv::decode::read_value(&mut &payload[..]).map(from_msgpack).map_err(|error| error.to_string()),
+        Format::Protobuf => Err("protobuf payload requires a schema".to_string()),
+    }
+}
+
+/// A value of a unit, the timestamp is set if the device sent one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub unit: String,
+    pub value: Value,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// Decoding settings shared by processing threads
+#[derive(Default)]
+pub struct Decoder {
+    pub senml: SenmlConfig,
+    pub flatten: FlattenConfig,
+    /// Protobuf schemas, protobuf payloads are rejected if not set
+    pub protobuf: Option<Registry>,
+    /// Parsed flatten.rules
+    rules: BTreeMap<String, Vec<(String, Path)>>,
+}
+
+impl Decoder {
+    /// Paths of flatten rules are checked here, so a typo is found on start
+    pub fn new(senml: SenmlConfig, flatten: FlattenConfig, protobuf: Option<Registry>) -> Result<Self, String> {
+        let rules = flatten::compile(&flatten.rules)?;
+        Ok(Decoder { senml, flatten, protobuf, rules })
+    }
+
+    pub fn decode(&self, format: Format, uid: &str, payload: &[u8]) -> Result<Value, String> {
+        match (format, &self.protobuf) {
+            (Format::Protobuf, Some(registry)) => registry.decode(uid, payload),
+            _ => decode(format, payload),
+        }
+    }
+
+    /// Message id and measurements of a decoded payload: a map of units and values or a SenML pack
+    pub fn measurements(&self, uid: &str, value: Value, now: DateTime<Utc>) -> Result<(Value, Vec<Measurement>), String> {
+        match value {
+            Value::Object(mut map) => {
+                let message_id = map.remove(MESSAGE_ID_KEY).unwrap_or(Value::Null);
+                let values = match self.rules.get(uid) {
+                    Some(rules) => {
+                        let payload = Value::Object(map);
+                        rules.iter()
+                            .filter_map(|(unit, path)| path.select(&payload).map(|value| (unit.clone(), value.clone())))
+                            .collect()
+                    },
+                    None => flatten::flatten(map, &self.flatten),
+                };
+                Ok((message_id, values.into_iter().map(|(unit, value)| Measurement { unit, value, timestamp: None }).collect()))
+            },
+            Value::Array(pack) => self.senml.resolve(pack, now).map(|measurements| (Value::Null, measurements)),
+            _ => Err("the message should be a map or a SenML pack".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_format_selection() {
+        assert_eq!(select("devices/sensor/data", None, Some(Format::Msgpack)), Format::Msgpack);
+        assert_eq!(select("devices/sensor/data/cbor", None, Some(Format::Msgpack)), Format::Cbor);
+        assert_eq!(select("devices/sensor/data/cbor", Some("application/json; charset=utf-8"), Some(Format::Msgpack)), Format::Json);
+        assert_eq!(select("devices/sensor/data/protobuf", None, None), Format::Protobuf);
+        assert_eq!(select("devices/other/data", None, None), Format::Json);
+    }
+
+    #[test]
+    fn test_binary_payloads_decode_to_json_map() {
+        // {"temp": 21.5, "count": 3, "on": true}
+        let cbor = [0xa3, 0x64, b't', b'e', b'm', b'p', 0xf9, 0x4d, 0x60, 0x65, b'c', b'o', b'u', b'n', b't', 0x03, 0x62, b'o', b'n', 0xf5];
+        let msgpack = [0x83, 0xa4, b't', b'e', b'm', b'p', 0xcb, 0x40, 0x35, 0x80, 0, 0, 0, 0, 0, 0xa5, b'c', b'o', b'u', b'n', b't', 0x03, 0xa2, b'o', b'n', 0xc3];
+        let expected = json!({ "temp": 21.5, "count": 3, "on": true });
+        assert_eq!(decode(Format::Cbor, &cbor).unwrap(), expected);
+        assert_eq!(decode(Format::Msgpack, &msgpack).unwrap(), expected);
+        assert_eq!(decode(Format::Json, expected.to_string().as_bytes()).unwrap(), expected);
+        assert!(decode(Format::Cbor, b"{").is_err());
+    }
+}
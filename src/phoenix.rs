@@ -1,19 +1,37 @@
 // WebSocket communication with Elixir Phoenix backend
 //  
 
-use log::{error, info, warn, trace};
+use log::{error, info, trace};
 use std::net::TcpStream;
 use std::thread;
 use std::time::Duration;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use websocket::client::sync::Client;
 use websocket::receiver::Reader;
 use websocket::result::{WebSocketError, WebSocketResult};
 use websocket::sender::Writer;
 use websocket::{ClientBuilder, Message, OwnedMessage};
 use crossbeam::channel;
-use serde_json::{Result, Value};
+use serde_json::{Result, Value, Map, json};
 
 use super::feeder::Command;
+use super::commands::COMMANDS_TOPIC;
+use super::backoff::{Backoff, ReconnectPolicy};
+
+// message reference counter, refs 1 is used by join messages
+static MESSAGE_REF: AtomicUsize = AtomicUsize::new(2);
+
+/// Build a join message, all topics are joined with join ref 1
+pub fn join_message(topic: &str) -> OwnedMessage {
+    OwnedMessage::Text(json!(["1", "1", topic, "phx_join", {}]).to_string())
+}
+
+/// Build a push message to a topic, the topic is joined by the sender before the first push
+pub fn push_message(topic: &str, event: &str, payload: Value) -> OwnedMessage {
+    let message_ref = MESSAGE_REF.fetch_add(1, Ordering::Relaxed);
+    OwnedMessage::Text(json!(["1", message_ref.to_string(), topic, event, payload]).to_string())
+}
 
 pub fn connect(host: &str) -> WebSocketResult<Client<TcpStream>> {
     match ClientBuilder::new(host) {
@@ -22,42 +40,69 @@ pub fn connect(host: &str) -> WebSocketResult<Client<TcpStream>> {
     }
 }
 
-pub fn reconnect(host: &str) -> Client<TcpStream> {
-    match connect(host) {
-        Ok(client) => return client,
-        Err(error) => {
-            error!("Error: {:?}", error);
-            warn!("Reconnect...");
-            thread::sleep(Duration::from_secs(5));
-            return reconnect(host);
-        } 
+/// Connect to the host, retry with backoff until the connection is established
+pub fn reconnect(host: &str, policy: ReconnectPolicy) -> Client<TcpStream> {
+    let mut backoff = Backoff::new("phoenix", policy);
+    loop {
+        match connect(host) {
+            Ok(client) => {
+                backoff.reset();
+                return client;
+            },
+            Err(error) => {
+                error!("Error: {:?}", error);
+                backoff.wait();
+            }
+        }
+    }
+}
+
+/// Device lifecycle events: created, updated, activated, deactivated, deleted.
+/// Inactive and deleted devices are kept in the devices list as inactive
+fn device_dispatcher(event: &str, map: &Map<String, Value>) -> Option<Command> {
+    let id = match map.get("id").and_then(|id| id.as_u64()) {
+        Some(id) => id as usize,
+        None => {
+            error!("Cannot parse ID: {:?}", map.get("id"));
+            return None;
+        }
+    };
+    let uid = map.get("uid")?.as_str()?.to_string();
+    let active = map.get("active").and_then(|active| active.as_bool()).unwrap_or(true);
+    let deleted = map.get("deleted_at").is_some_and(|deleted_at| !deleted_at.is_null());
+
+    match event {
+        "created" | "updated" if active && !deleted => Some(Command::Activate(id, uid)),
+        "created" | "updated" | "deactivated" | "deleted" => Some(Command::Deactivate(id, uid)),
+        "activated" => Some(Command::Activate(id, uid)),
+        _ => None,
     }
 }
 
 fn message_dispatcher(message: &Value) -> Option<Command> {
     if let Value::Array(result) = message {
+        if result.len() > 4 && result[2] == Value::String(COMMANDS_TOPIC.to_string()) && result[3] == Value::String("send".to_string()) {
+            if let Some(Value::String(uid)) = result[4].get("uid") {
+                return Some(Command::DeviceCommand(uid.clone(), result[4].clone()));
+            }
+            error!("Command without device uid: {}", result[4]);
+            return None;
+        }
+        if result.len() > 4 && result[3] == Value::String("resync".to_string()) {
+            // on-demand resynchronisation of caches with DB
+            return Some(Command::Resync);
+        }
+        if result.len() > 4 && result[2] == Value::String("devices".to_string()) {
+            if let (Some(event), Value::Object(map)) = (result[3].as_str(), &result[4]) {
+                return device_dispatcher(event, map);
+            }
+        }
         if result.len() > 4 {
             if result[3] == Value::String("created".to_string()) {
                 if let Value::Object(map) = &result[4] {
-                    if result[2] == Value::String("devices".to_string()) {
+                    if result[2] == Value::String("units".to_string()) {
                         if let Some(id) = map.get("id") {
-                            if let Some(uid) = map.get("uid") {
-                                // send message to the response channel
-                                if let Value::Number(nid) = id {
-                                    match nid.as_u64() {
-                                        Some(num) => {
-                                            return Some(Command::Activate(num as usize, uid.to_string()));
-                                        },
-                                        _ => {
-                                            error!("Cannot parse ID: {}", id.to_string());
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    } else if result[2] == Value::String("units".to_string()) {
-                        if let Some(id) = map.get("id") {
-                            if let Some(name) = map.get("name") {
+                            if let Some(name) = map.get("name").and_then(|name| name.as_str()) {
                                 if let Value::Number(nid) = id {
                                     match nid.as_u64() {
                                         Some(num) => {
@@ -78,7 +123,7 @@ fn message_dispatcher(message: &Value) -> Option<Command> {
     None
 }
 
-pub fn receiver(mut receiver: Reader<TcpStream>, sender_channel: channel::Sender<websocket::OwnedMessage>, storage_channel: channel::Sender<Command>) {
+pub fn receiver(mut receiver: Reader<TcpStream>, sender_channel: channel::Sender<websocket::OwnedMessage>, storage_channel: channel::Sender<Command>, commands_channel: channel::Sender<Command>) {
     for message in receiver.incoming_messages() {
         let message = match message {
             Ok(m) => m,
@@ -130,6 +175,11 @@ pub fn receiver(mut receiver: Reader<TcpStream>, sender_channel: channel::Sender
                 info!("Get message: {}", result);
 
                 match message_dispatcher(&result) {
+                    Some(message @ Command::DeviceCommand(_, _)) => {
+                        if let Err(error) = commands_channel.send(message) {
+                            error!("WebSocket Receiver error: {}", error);
+                        }
+                    },
                     Some(message) => {
                         if let Err(error) = storage_channel.send(message) {
                             error!("WebSocket Receiver error: {}", error);
@@ -144,14 +194,50 @@ pub fn receiver(mut receiver: Reader<TcpStream>, sender_channel: channel::Sender
     }
 }
 
-pub fn sender(mut tcp_stream: Writer<TcpStream>, channel: channel::Receiver<OwnedMessage>) {
-    while let Ok(message) = channel.recv() {
+/// Return topic and event of a text message
+fn topic_event(message: &OwnedMessage) -> Option<(String, String)> {
+    if let OwnedMessage::Text(text) = message {
+        if let Ok(Value::Array(result)) = serde_json::from_str::<Value>(text) {
+            if let (Some(Value::String(topic)), Some(Value::String(event))) = (result.get(2), result.get(3)) {
+                return Some((topic.clone(), event.clone()));
+            }
+        }
+    }
+    None
+}
+
+/// Send messages from the connection channel and from the outbound channel (pushes from other threads)
+/// The outbound channel outlives connection, so messages sent during reconnection are delivered later.
+/// A topic is joined before the first push to it, joined topics are tracked per connection
+pub fn sender(mut tcp_stream: Writer<TcpStream>, channel: channel::Receiver<OwnedMessage>, outbound: channel::Receiver<OwnedMessage>) {
+    let mut joined: BTreeSet<String> = BTreeSet::new();
+    loop {
+        let message = crossbeam::select! {
+            recv(channel) -> message => message,
+            recv(outbound) -> message => message,
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(_) => return,
+        };
         match message {
             OwnedMessage::Close(None) => {
                 return;
             }
             _ => {}
         }
+
+        if let Some((topic, event)) = topic_event(&message) {
+            if event == "phx_join" {
+                joined.insert(topic);
+            } else if topic != "phoenix" && !joined.contains(&topic) {
+                if let Err(e) = tcp_stream.send_message(&join_message(&topic)) {
+                    error!("WebSocket Sender error: {}", e);
+                    return;
+                }
+                joined.insert(topic);
+            }
+        }
     
         match tcp_stream.send_message(&message) {
             Ok(()) => (),
@@ -179,18 +265,30 @@ pub fn pinger(interval: u64, sender_channel: channel::Sender<OwnedMessage>) {
 }
 
 pub fn join_phoenix(sender_channel: channel::Sender<OwnedMessage>) {
-    // send join message
-    let join_message =
-    OwnedMessage::Text("[\"1\",\"1\",\"devices\", \"phx_join\", {}]".to_string());
-    
-    if let Err(error) = sender_channel.send(join_message) {
-        error!("Cannot send join message due to error: {}", error);
+    // send join messages
+    for topic in &["devices", "units", COMMANDS_TOPIC] {
+        if let Err(error) = sender_channel.send(join_message(topic)) {
+            error!("Cannot send join message due to error: {}", error);
+        }
     }
+}
 
-    let join_message =
-    OwnedMessage::Text("[\"1\",\"1\",\"units\", \"phx_join\", {}]".to_string());
-    
-    if let Err(error) = sender_channel.send(join_message) {
-        error!("Cannot send join message due to error: {}", error);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_activation_events() {
+        let created = json!(["1", "2", "devices", "created", {"id": 5, "uid": "uid-5", "active": true}]);
+        match message_dispatcher(&created) {
+            Some(Command::Activate(5, uid)) => assert_eq!(uid, "uid-5"),
+            command => panic!("unexpected command {:?}", command),
+        }
+
+        let updated = json!(["1", "3", "devices", "updated", {"id": 5, "uid": "uid-5", "active": false}]);
+        assert!(matches!(message_dispatcher(&updated), Some(Command::Deactivate(5, _))));
+
+        let deleted = json!(["1", "4", "devices", "updated", {"id": 5, "uid": "uid-5", "deleted_at": "2021-04-01T10:00:00"}]);
+        assert!(matches!(message_dispatcher(&deleted), Some(Command::Deactivate(5, _))));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,113 @@
+//** MQTT republish sink */
+//** Resolved records are published by the Publisher thread to per unit topics, fennec/<device_id>/<unit> by default, */
+//** as {"device_id", "uid", "unit_id", "unit", "value", "type", "timestamp", "ts"} with the value kept typed */
+
+use crossbeam::channel;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::feeder::Command;
+use super::sink::{BatchPolicy, Record, Sink, SinkError};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RepublishConfig {
+    /// {device_id}, {uid} and {unit} are replaced with the record fields
+    pub topic: String,
+    pub qos: i32,
+    /// Keep the last value of a unit on the broker for new subscribers
+    pub retain: bool,
+    pub batch: BatchPolicy,
+}
+
+impl Default for RepublishConfig {
+    fn default() -> Self {
+        RepublishConfig {
+            topic: "fennec/{device_id}/{unit}".to_string(),
+            qos: 1,
+            retain: false,
+            batch: BatchPolicy::default(),
+        }
+    }
+}
+
+/// A topic level must not contain separators and wildcards
+fn topic_level(name: &str) -> String {
+    name.chars().map(|c| if c == '/' || c == '+' || c == '#' { '_' } else { c }).collect()
+}
+
+pub fn topic(template: &str, record: &Record) -> String {
+    template.replace("{device_id}", &record.device_id.to_string())
+        .replace("{uid}", &topic_level(&record.uid))
+        .replace("{unit}", &topic_level(&record.unit))
+}
+
+fn value_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+pub fn payload(record: &Record) -> Value {
+    let mut payload = record.to_json();
+    payload["type"] = Value::from(value_type(&record.value));
+    payload["ts"] = Value::from(record.timestamp.timestamp_millis());
+    payload
+}
+
+pub struct RepublishSink {
+    config: RepublishConfig,
+    publisher_sender: channel::Sender<Command>,
+}
+
+impl RepublishSink {
+    pub fn new(config: RepublishConfig, publisher_sender: channel::Sender<Command>) -> Self {
+        RepublishSink { config, publisher_sender }
+    }
+}
+
+impl Sink for RepublishSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn write(&mut self, records: &[Record]) -> Result<(), SinkError> {
+        for record in records {
+            let message = Command::Republish(topic(&self.config.topic, record), payload(record).to_string(), self.config.qos, self.config.retain);
+            self.publisher_sender.send(message).map_err(|error| SinkError::Retry(error.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+    use serde_json::json;
+
+    fn record() -> Record {
+        Record { device_id: 7, uid: "dev1".to_string(), unit_id: 3, unit: "temp/inner".to_string(), value: json!(21.5),
+            timestamp: Utc.timestamp(1_600_000_000, 0) }
+    }
+
+    #[test]
+    fn test_topic_template() {
+        assert_eq!(topic(&RepublishConfig::default().topic, &record()), "fennec/7/temp_inner");
+        assert_eq!(topic("devices/{uid}/{unit}", &record()), "devices/dev1/temp_inner");
+    }
+
+    #[test]
+    fn test_typed_payload() {
+        let payload = payload(&record());
+        assert_eq!(payload["value"], json!(21.5));
+        assert_eq!(payload["type"], json!("number"));
+        assert_eq!(payload["ts"], json!(1_600_000_000_000i64));
+        assert_eq!(payload["timestamp"], json!("2020-09-13T12:26:40.000Z"));
+    }
+}
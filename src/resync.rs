@@ -0,0 +1,123 @@
+//** Cache resynchronisation */
+//** Caches are compared with the data loaded from DB, the difference is applied at once by the thread owning the cache */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use log::info;
+
+use super::feeder::DevicesUnitsStorage;
+use super::metrics;
+
+#[derive(Debug, PartialEq)]
+pub struct Diff<K, V> {
+    pub added: Vec<(K, V)>,
+    pub removed: Vec<K>,
+    pub changed: Vec<(K, V)>, // new values, e.g. activation changes of devices
+}
+
+impl<K: Ord + Clone + Debug, V: PartialEq + Clone + Debug> Diff<K, V> {
+    /// Difference which turns `cache` into `loaded`
+    pub fn new(cache: &BTreeMap<K, V>, loaded: &BTreeMap<K, V>) -> Self {
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        for (key, value) in loaded {
+            match cache.get(key) {
+                None => added.push((key.clone(), value.clone())),
+                Some(cached) if cached != value => changed.push((key.clone(), value.clone())),
+                _ => {}
+            }
+        }
+        let removed = cache.keys().filter(|key| !loaded.contains_key(key)).cloned().collect();
+        Diff { added, removed, changed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn apply(self, cache: &mut BTreeMap<K, V>) {
+        for key in self.removed {
+            cache.remove(&key);
+        }
+        for (key, value) in self.added.into_iter().chain(self.changed) {
+            cache.insert(key, value);
+        }
+    }
+
+    /// Log and count the changes
+    pub fn report(&self, cache: &str) {
+        if self.is_empty() {
+            return;
+        }
+        info!("Resync {}: added {:?}, removed {:?}, changed {:?}", cache, self.added, self.removed, self.changed);
+        metrics::add(&format!("resync_changes_total{{cache=\"{}\",change=\"added\"}}", cache), self.added.len() as u64);
+        metrics::add(&format!("resync_changes_total{{cache=\"{}\",change=\"removed\"}}", cache), self.removed.len() as u64);
+        metrics::add(&format!("resync_changes_total{{cache=\"{}\",change=\"changed\"}}", cache), self.changed.len() as u64);
+    }
+}
+
+/// Merge the loaded units-devices relationship matrix into the cache, return amount of added and removed links.
+/// Recent links (unit_id, device_id) are kept even if they are not loaded: their writes could be still queued
+pub fn resync_links(cache: &mut DevicesUnitsStorage, loaded: &DevicesUnitsStorage, recent: &BTreeSet<(usize, usize)>) -> (usize, usize) {
+    let cached: BTreeSet<(usize, usize)> = cache.iter().map(|(unit_id, device_id, _)| (unit_id, device_id)).collect();
+    let loaded: BTreeSet<(usize, usize)> = loaded.iter().map(|(unit_id, device_id, _)| (unit_id, device_id)).collect();
+
+    let removed: Vec<&(usize, usize)> = cached.difference(&loaded).filter(|link| !recent.contains(link)).collect();
+    let added: Vec<&(usize, usize)> = loaded.difference(&cached).collect();
+    for (unit_id, device_id) in &removed {
+        cache.remove(*unit_id, *device_id);
+    }
+    for (unit_id, device_id) in &added {
+        cache.add(*unit_id, *device_id, true);
+    }
+    if !added.is_empty() || !removed.is_empty() {
+        info!("Resync units-devices: added {:?}, removed {:?}", added, removed);
+        metrics::add("resync_changes_total{cache=\"devices_units\",change=\"added\"}", added.len() as u64);
+        metrics::add("resync_changes_total{cache=\"devices_units\",change=\"removed\"}", removed.len() as u64);
+    }
+    (added.len(), removed.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::feeder::DeviceMap;
+
+    #[test]
+    fn test_devices_diff() {
+        let mut cache = DeviceMap::new();
+        cache.insert("a".to_string(), Some(1));
+        cache.insert("b".to_string(), Some(2));
+        cache.insert("c".to_string(), Some(3));
+        let mut loaded = DeviceMap::new();
+        loaded.insert("a".to_string(), Some(1));
+        loaded.insert("b".to_string(), None);
+        loaded.insert("d".to_string(), Some(4));
+
+        let diff = Diff::new(&cache, &loaded);
+        assert_eq!(diff.added, vec![("d".to_string(), Some(4))]);
+        assert_eq!(diff.removed, vec!["c".to_string()]);
+        assert_eq!(diff.changed, vec![("b".to_string(), None)]);
+
+        diff.apply(&mut cache);
+        assert_eq!(cache, loaded);
+    }
+
+    #[test]
+    fn test_resync_links() {
+        let mut cache = DevicesUnitsStorage::new();
+        cache.add(1, 1, true);
+        cache.add(2, 1, true);
+        cache.add(4, 1, true);
+        let mut loaded = DevicesUnitsStorage::new();
+        loaded.add(1, 1, true);
+        loaded.add(3, 2, true);
+        // the link of unit 4 is not written yet
+        let recent: BTreeSet<(usize, usize)> = vec![(4, 1)].into_iter().collect();
+
+        assert_eq!(resync_links(&mut cache, &loaded, &recent), (1, 1));
+        assert_eq!(cache.get(2, 1), None);
+        assert_eq!(cache.get(3, 2), Some(true));
+        assert_eq!(cache.get(4, 1), Some(true));
+    }
+}
@@ -0,0 +1,206 @@
+//** Retention of raw records */
+//** Raw records older than the retention period of their unit are rolled into records_hourly and records_daily */
+//** (min, max, avg of numeric values, count, last value) and deleted in bounded batches, day by day. A bucket keeps */
+//** the id of the last record rolled into it, late records are merged into the existing bucket on the next run */
+
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+use log::{info, error};
+use mysql::prelude::*;
+use mysql::{Pool, PooledConn, TxOpts};
+use chrono::prelude::*;
+use chrono::Duration as Days;
+use serde::Deserialize;
+
+use super::backoff::ReconnectPolicy;
+use super::db;
+use super::metrics;
+
+/// Aggregate tables and expressions truncating inserted_at to their bucket
+const AGGREGATES: &[(&str, &str)] = &[
+    ("records_hourly", "TIMESTAMP(DATE(records.inserted_at), MAKETIME(HOUR(records.inserted_at), 0, 0))"),
+    ("records_daily", "TIMESTAMP(DATE(records.inserted_at))"),
+];
+
+/// Values are stored as JSON, only numbers are taken into min, max and avg
+const NUMERIC_VALUE: &str = "IF(records.value REGEXP '^-?[0-9]+([.][0-9]+)?([eE][-+]?[0-9]+)?$', records.value + 0, NULL)";
+
+/// Merge of new values into an existing bucket of the table, assignments see the values updated before them:
+/// avg and last value are computed before the count and the time of the last value
+fn merge(table: &str) -> String {
+    format!("min_value = LEAST(COALESCE({t}.min_value, VALUES(min_value)), COALESCE(VALUES(min_value), {t}.min_value)),
+        max_value = GREATEST(COALESCE({t}.max_value, VALUES(max_value)), COALESCE(VALUES(max_value), {t}.max_value)),
+        avg_value = IF({t}.avg_value IS NULL OR VALUES(avg_value) IS NULL, COALESCE({t}.avg_value, VALUES(avg_value)),
+            ({t}.avg_value * {t}.value_count + VALUES(avg_value) * VALUES(value_count)) / ({t}.value_count + VALUES(value_count))),
+        last_value = IF({t}.last_value_at IS NULL OR VALUES(last_value_at) >= {t}.last_value_at, VALUES(last_value), {t}.last_value),
+        value_count = {t}.value_count + VALUES(value_count),
+        last_value_at = GREATEST(COALESCE({t}.last_value_at, VALUES(last_value_at)), VALUES(last_value_at)),
+        last_record_id = GREATEST({t}.last_record_id, VALUES(last_record_id)),
+        updated_at = VALUES(updated_at)", t = table)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Seconds between retention runs, 0 disables retention
+    pub interval: u64,
+    /// Days to keep raw records, 0 keeps them forever
+    pub raw_days: u32,
+    /// Per unit overrides of raw_days by unit name
+    pub units: BTreeMap<String, u32>,
+    /// Max amount of records removed by one DELETE
+    pub batch_size: u64,
+    /// Pause between DELETE batches, in milliseconds
+    pub batch_pause: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            interval: 3600,
+            raw_days: 0,
+            units: BTreeMap::new(),
+            batch_size: 1000,
+            batch_pause: 100,
+        }
+    }
+}
+
+impl RetentionConfig {
+    pub fn raw_days(&self, unit: &str) -> u32 {
+        self.units.get(unit).copied().unwrap_or(self.raw_days)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.interval > 0 && (self.raw_days > 0 || self.units.values().any(|days| *days > 0))
+    }
+}
+
+/// Days with records to roll up: from the oldest record up to `raw_days` before today
+pub fn expired_days(oldest: NaiveDate, today: NaiveDate, raw_days: u32) -> Vec<NaiveDate> {
+    let cutoff = today - Days::days(raw_days as i64);
+    let mut days = Vec::new();
+    let mut day = oldest;
+    while day < cutoff {
+        days.push(day);
+        day = day.succ();
+    }
+    days
+}
+
+fn day_start(day: NaiveDate) -> String {
+    day.format("%Y-%m-%d 00:00:00").to_string()
+}
+
+/// Roll records of a unit for a day into aggregates, return the id of the last record of the day if there are records.
+/// Records already rolled into a bucket (up to its last_record_id) are skipped, so a day interrupted during deletion
+/// is not counted twice and late records are added to their buckets
+fn roll_up(conn: &mut PooledConn, unit_id: usize, day: NaiveDate) -> mysql::Result<Option<u64>> {
+    let utc_timestamp = db::timestamp();
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+    let max_id: Option<Option<u64>> = tx.exec_first("SELECT MAX(id) FROM records WHERE unit_id = ? AND inserted_at >= ? AND inserted_at < ?",
+        (unit_id, day_start(day), day_start(day.succ())))?;
+    let max_id = match max_id.flatten() {
+        Some(max_id) => max_id,
+        None => return Ok(None),
+    };
+    for (table, bucket) in AGGREGATES {
+        tx.exec_drop(format!("INSERT INTO {table} (device_id, unit_id, bucket, min_value, max_value, avg_value, value_count, last_value,
+                    last_value_at, last_record_id, inserted_at, updated_at)
+                SELECT records.device_id, records.unit_id, {bucket} AS bucket_start, MIN({numeric}), MAX({numeric}), AVG({numeric}), COUNT(*),
+                    SUBSTRING_INDEX(GROUP_CONCAT(records.value ORDER BY records.inserted_at DESC, records.id DESC SEPARATOR '\\n'), '\\n', 1),
+                    MAX(records.inserted_at), MAX(records.id), ?, ?
+                FROM records LEFT JOIN {table} target ON target.device_id = records.device_id AND target.unit_id = records.unit_id
+                    AND target.bucket = {bucket}
+                WHERE records.unit_id = ? AND records.inserted_at >= ? AND records.inserted_at < ?
+                    AND records.id <= ? AND records.id > COALESCE(target.last_record_id, 0)
+                GROUP BY records.device_id, records.unit_id, bucket_start
+                ON DUPLICATE KEY UPDATE {merge}", table = table, bucket = bucket, numeric = NUMERIC_VALUE, merge = merge(table)),
+            (&utc_timestamp, &utc_timestamp, unit_id, day_start(day), day_start(day.succ()), max_id))?;
+    }
+    tx.commit()?;
+    Ok(Some(max_id))
+}
+
+/// Delete rolled up records of a unit for a day in batches, up to the last rolled up id.
+/// Return amount of deleted records
+fn delete_day(conn: &mut PooledConn, config: &RetentionConfig, unit_id: usize, day: NaiveDate, max_id: u64) -> mysql::Result<u64> {
+    let batch_size = config.batch_size.max(1);
+    let mut deleted = 0;
+    loop {
+        conn.exec_drop("DELETE FROM records WHERE unit_id = ? AND inserted_at >= ? AND inserted_at < ? AND id <= ? LIMIT ?",
+            (unit_id, day_start(day), day_start(day.succ()), max_id, batch_size))?;
+        let affected = conn.affected_rows();
+        deleted += affected;
+        metrics::add("retention_deleted_records_total", affected);
+        if affected < batch_size {
+            return Ok(deleted);
+        }
+        thread::sleep(Duration::from_millis(config.batch_pause));
+    }
+}
+
+/// Apply retention to all units once
+pub fn apply(pool: &Pool, config: &RetentionConfig) -> mysql::Result<()> {
+    let mut conn = pool.get_conn()?;
+    let today = Utc::now().naive_utc().date();
+    for (name, unit_id) in db::load_units(&mut conn)? {
+        let raw_days = config.raw_days(&name);
+        if raw_days == 0 {
+            continue;
+        }
+        let oldest: Option<Option<String>> = conn.exec_first("SELECT DATE_FORMAT(MIN(inserted_at), '%Y-%m-%d') FROM records WHERE unit_id = ?",
+            (unit_id,))?;
+        let oldest = match oldest.flatten().and_then(|day| NaiveDate::parse_from_str(&day, "%Y-%m-%d").ok()) {
+            Some(oldest) => oldest,
+            None => continue,
+        };
+        for day in expired_days(oldest, today, raw_days) {
+            let max_id = match roll_up(&mut conn, unit_id, day)? {
+                Some(max_id) => max_id,
+                None => continue,
+            };
+            let deleted = delete_day(&mut conn, config, unit_id, day, max_id)?;
+            info!("Retention: rolled up and deleted {} records of unit {} for {}", deleted, &name, day);
+        }
+    }
+    metrics::increment("retention_runs_total");
+    Ok(())
+}
+
+/// A thread applying retention every `interval` seconds
+pub fn run(db_host: &str, config: &RetentionConfig, policy: ReconnectPolicy) {
+    let pool = db::connect(db_host, policy);
+    loop {
+        thread::sleep(Duration::from_secs(config.interval));
+        if let Err(error) = apply(&pool, config) {
+            // the next run continues from the same day
+            error!("Retention thread error: {}", error);
+            metrics::increment("retention_errors_total");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_days() {
+        let today = NaiveDate::from_ymd(2021, 3, 10);
+        let days = expired_days(NaiveDate::from_ymd(2021, 3, 1), today, 7);
+        assert_eq!(days, vec![NaiveDate::from_ymd(2021, 3, 1), NaiveDate::from_ymd(2021, 3, 2)]);
+        assert!(expired_days(NaiveDate::from_ymd(2021, 3, 5), today, 7).is_empty());
+    }
+
+    #[test]
+    fn test_unit_overrides() {
+        let mut config = RetentionConfig { raw_days: 30, ..RetentionConfig::default() };
+        config.units.insert("temperature".to_string(), 7);
+        assert_eq!(config.raw_days("temperature"), 7);
+        assert_eq!(config.raw_days("humidity"), 30);
+        assert!(config.is_enabled());
+        assert!(!RetentionConfig::default().is_enabled());
+    }
+}
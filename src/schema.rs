@@ -0,0 +1,204 @@
+//** Protobuf schema registry */
+//** Message descriptors are loaded from a local directory of .proto files and descriptor sets (.desc, .pb). */
+//** A device type selects the message of its devices, payloads are decoded to a map of units and values */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::info;
+use protobuf::Message;
+use protobuf::descriptor::{FileDescriptorProto, FileDescriptorSet};
+use protobuf::reflect::{FileDescriptor, MessageDescriptor, ReflectValueRef, ReflectFieldRef, RuntimeType, Syntax};
+use protobuf::MessageDyn;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProtobufConfig {
+    /// Directory with .proto files and descriptor sets, .proto imports are resolved from it
+    pub schemas: String,
+    /// Message and field mapping by device type
+    pub device_types: BTreeMap<String, DeviceType>,
+    /// Device type by UID, payloads of these devices are protobuf by default
+    pub devices: BTreeMap<String, String>,
+}
+
+impl Default for ProtobufConfig {
+    fn default() -> Self {
+        ProtobufConfig {
+            schemas: "schemas".to_string(),
+            device_types: BTreeMap::new(),
+            devices: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DeviceType {
+    /// Full message name, e.g. fennec.BatterySensor
+    pub message: String,
+    /// Unit by field name, fields without a mapping keep their names
+    pub units: BTreeMap<String, String>,
+}
+
+pub struct Registry {
+    types: BTreeMap<String, (MessageDescriptor, BTreeMap<String, String>)>,
+    devices: BTreeMap<String, String>,
+}
+
+fn files(dir: &Path, found: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|error| format!("cannot read {}: {}", dir.display(), error))?;
+    for entry in entries {
+        let path = entry.map_err(|error| error.to_string())?.path();
+        if path.is_dir() {
+            files(&path, found)?;
+        } else {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|ext| ext.to_str())
+}
+
+/// Parse .proto files and read descriptor sets of the directory
+fn load_descriptors(dir: &Path) -> Result<Vec<FileDescriptor>, String> {
+    let mut found = Vec::new();
+    files(dir, &mut found)?;
+    found.sort();
+
+    let mut protos: Vec<FileDescriptorProto> = Vec::new();
+    let sources: Vec<&PathBuf> = found.iter().filter(|path| extension(path) == Some("proto")).collect();
+    if !sources.is_empty() {
+        let parsed = protobuf_parse::Parser::new().pure().include(dir).inputs(sources).parse_and_typecheck()
+            .map_err(|error| error.to_string())?;
+        protos.extend(parsed.file_descriptors);
+    }
+    for path in found.iter().filter(|path| matches!(extension(path), Some("desc") | Some("pb"))) {
+        let bytes = fs::read(path).map_err(|error| format!("cannot read {}: {}", path.display(), error))?;
+        let set = FileDescriptorSet::parse_from_bytes(&bytes).map_err(|error| format!("{}: {}", path.display(), error))?;
+        protos.extend(set.file);
+    }
+
+    // a file could come from both a .proto and a descriptor set
+    let mut names = Vec::new();
+    protos.retain(|proto| {
+        let unique = !names.contains(&proto.name().to_string());
+        names.push(proto.name().to_string());
+        unique
+    });
+    FileDescriptor::new_dynamic_fds(protos, &[]).map_err(|error| error.to_string())
+}
+
+fn find_message(files: &[FileDescriptor], full_name: &str) -> Option<MessageDescriptor> {
+    files.iter().find_map(|file| {
+        let relative = if file.package().is_empty() {
+            Some(full_name)
+        } else {
+            full_name.strip_prefix(file.package()).and_then(|name| name.strip_prefix('.'))
+        };
+        relative.and_then(|name| file.message_by_package_relative_name(name))
+    })
+}
+
+fn to_json(value: ReflectValueRef) -> Value {
+    match value {
+        ReflectValueRef::U32(number) => Value::from(number),
+        ReflectValueRef::U64(number) => Value::from(number),
+        ReflectValueRef::I32(number) => Value::from(number),
+        ReflectValueRef::I64(number) => Value::from(number),
+        ReflectValueRef::F32(number) => Value::from(number as f64),
+        ReflectValueRef::F64(number) => Value::from(number),
+        ReflectValueRef::Bool(flag) => Value::Bool(flag),
+        ReflectValueRef::String(text) => Value::String(text.to_string()),
+        ReflectValueRef::Bytes(bytes) => Value::String(hex::encode(bytes)),
+        ReflectValueRef::Enum(descriptor, number) => descriptor.value_by_number(number)
+            .map(|value| Value::String(value.name().to_string()))
+            .unwrap_or_else(|| Value::from(number)),
+        ReflectValueRef::Message(message) => message_to_json(&*message),
+    }
+}
+
+/// Fields without presence in proto3 (plain scalars) are always written, a zero reading is a reading;
+/// other fields are written if they are set
+fn message_to_json(message: &dyn MessageDyn) -> Value {
+    let descriptor = message.descriptor_dyn();
+    let proto3 = descriptor.file_descriptor().syntax() == Syntax::Proto3;
+    let mut map = Map::new();
+    for field in descriptor.fields() {
+        let value = match field.get_reflect(message) {
+            ReflectFieldRef::Optional(optional) => match optional.value() {
+                Some(value) => to_json(value),
+                None if proto3 && field.containing_oneof_including_synthetic().is_none()
+                    && !matches!(field.singular_runtime_type(), RuntimeType::Message(_)) => to_json(field.get_singular_field_or_default(message)),
+                None => continue,
+            },
+            ReflectFieldRef::Repeated(repeated) => Value::Array(repeated.into_iter().map(to_json).collect()),
+            ReflectFieldRef::Map(entries) => Value::Object((&entries).into_iter()
+                .map(|(key, value)| (match to_json(key) { Value::String(key) => key, key => key.to_string() }, to_json(value)))
+                .collect()),
+        };
+        map.insert(field.name().to_string(), value);
+    }
+    Value::Object(map)
+}
+
+impl Registry {
+    pub fn load(config: &ProtobufConfig) -> Result<Self, String> {
+        let files = load_descriptors(Path::new(&config.schemas))?;
+        let mut types = BTreeMap::new();
+        for (name, device_type) in &config.device_types {
+            let descriptor = find_message(&files, &device_type.message)
+                .ok_or_else(|| format!("no message {} of device type {} in {}", device_type.message, name, config.schemas))?;
+            types.insert(name.clone(), (descriptor, device_type.units.clone()));
+        }
+        info!("Loaded {} protobuf files and {} device types from {}", files.len(), types.len(), config.schemas);
+        Ok(Registry { types, devices: config.devices.clone() })
+    }
+
+    /// Decode a payload of a device by the message of its type, fields are renamed to units
+    pub fn decode(&self, uid: &str, payload: &[u8]) -> Result<Value, String> {
+        let device_type = self.devices.get(uid).ok_or_else(|| format!("device {} has no protobuf device type", uid))?;
+        let (descriptor, units) = self.types.get(device_type).ok_or_else(|| format!("unknown device type {}", device_type))?;
+        let message = descriptor.parse_from_bytes(payload).map_err(|error| error.to_string())?;
+        match message_to_json(&*message) {
+            Value::Object(map) => Ok(Value::Object(map.into_iter()
+                .map(|(field, value)| (units.get(&field).cloned().unwrap_or(field), value))
+                .collect())),
+            other => Ok(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use serde_json::json;
+
+    #[test]
+    fn test_decode_by_device_type() {
+        let dir = env::temp_dir().join(format!("ffeeder-schemas-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("battery.proto"), "syntax = \"proto3\";\npackage fennec;\n\
+            message Battery { float voltage = 1; sint32 temp = 2; string state = 3; optional uint32 cycles = 4; }\n").unwrap();
+
+        let mut config = ProtobufConfig { schemas: dir.to_string_lossy().to_string(), ..ProtobufConfig::default() };
+        let mut units = BTreeMap::new();
+        units.insert("voltage".to_string(), "battery_voltage".to_string());
+        config.device_types.insert("battery".to_string(), DeviceType { message: "fennec.Battery".to_string(), units });
+        config.devices.insert("sensor-1".to_string(), "battery".to_string());
+        let registry = Registry::load(&config).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // voltage = 3.5 (fixed32), temp = -3 (zigzag), state and cycles are not set
+        let payload = [0x0d, 0x00, 0x00, 0x60, 0x40, 0x10, 0x05];
+        assert_eq!(registry.decode("sensor-1", &payload).unwrap(), json!({ "battery_voltage": 3.5, "temp": -3, "state": "" }));
+        assert!(registry.decode("sensor-2", &payload).is_err());
+        assert!(registry.decode("sensor-1", &[0x0d, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//** SenML (RFC 8428) packs */
+//** A pack is an array of records, base fields (bn, bt, bu, bv, bs) apply to the record and the following ones. */
+//** Every resolved record becomes a measurement of a feeder unit with its own timestamp */
+
+use std::collections::BTreeMap;
+use log::warn;
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::payload::Measurement;
+
+/// Times below 2^28 seconds are relative to the current time
+const RELATIVE_TIME_LIMIT: f64 = 268_435_456.0;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SenmlConfig {
+    /// Feeder unit by SenML name, e.g. {"urn:dev:ow:10e2073a01080063:temp": "temperature", "humidity": "humidity"};
+    /// the full name is looked up first, then the name without base name. Names with the SenML unit,
+    /// e.g. {"temp:Cel": "temperature", "temp:Far": "temperature_f"}, are looked up before the names alone.
+    /// Without a mapping the record name (or the base name if the record has no name) is used
+    pub units: BTreeMap<String, String>,
+}
+
+/// Names of CBOR integer labels (RFC 8428, section 6)
+fn label(key: &str) -> &str {
+    match key {
+        "-1" => "bver",
+        "-2" => "bn",
+        "-3" => "bt",
+        "-4" => "bu",
+        "-5" => "bv",
+        "-6" => "bs",
+        "0" => "n",
+        "1" => "u",
+        "2" => "v",
+        "3" => "vs",
+        "4" => "vb",
+        "5" => "s",
+        "6" => "t",
+        "7" => "ut",
+        "8" => "vd",
+        other => other,
+    }
+}
+
+#[derive(Default)]
+struct Base {
+    name: String,
+    time: f64,
+    unit: String,
+    value: f64,
+    sum: f64,
+}
+
+/// Add a base value to a number, the number is kept as is if there is nothing to add
+fn add(value: &Value, base: f64) -> Value {
+    match value.as_f64() {
+        Some(number) if base != 0.0 => Value::from(number + base),
+        _ => value.clone(),
+    }
+}
+
+fn timestamp(time: f64, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if time.abs() < RELATIVE_TIME_LIMIT {
+        Some(now + Duration::milliseconds((time * 1000.0) as i64))
+    } else {
+        Utc.timestamp_opt(time.trunc() as i64, (time.fract() * 1e9) as u32).single()
+    }
+}
+
+impl SenmlConfig {
+    fn unit(&self, name: &str, record_name: &str, unit: &str) -> String {
+        let with_unit = |name: &str| if unit.is_empty() { None } else { self.units.get(&format!("{}:{}", name, unit)) };
+        with_unit(name)
+            .or_else(|| with_unit(record_name))
+            .or_else(|| self.units.get(name))
+            .or_else(|| self.units.get(record_name))
+            .cloned()
+            .unwrap_or_else(|| if record_name.is_empty() { name.to_string() } else { record_name.to_string() })
+    }
+
+    /// Resolve records of a pack, records without a name or a value are skipped
+    pub fn resolve(&self, pack: Vec<Value>, now: DateTime<Utc>) -> Result<Vec<Measurement>, String> {
+        let mut base = Base::default();
+        let mut measurements = Vec::new();
+
+        for entry in pack {
+            let record: Map<String, Value> = match entry {
+                Value::Object(map) => map.into_iter().map(|(key, value)| (label(&key).to_string(), value)).collect(),
+                other => return Err(format!("a SenML record should be a map, got {}", other)),
+            };
+
+            if let Some(name) = record.get("bn").and_then(Value::as_str) {
+                base.name = name.to_string();
+            }
+            if let Some(time) = record.get("bt").and_then(Value::as_f64) {
+                base.time = time;
+            }
+            if let Some(unit) = record.get("bu").and_then(Value::as_str) {
+                base.unit = unit.to_string();
+            }
+            if let Some(value) = record.get("bv").and_then(Value::as_f64) {
+                base.value = value;
+            }
+            if let Some(sum) = record.get("bs").and_then(Value::as_f64) {
+                base.sum = sum;
+            }
+
+            let record_name = record.get("n").and_then(Value::as_str).unwrap_or("");
+            let name = format!("{}{}", base.name, record_name);
+            if name.is_empty() {
+                warn!("SenML record without a name is skipped");
+                continue;
+            }
+
+            let value = if let Some(value) = record.get("v") {
+                add(value, base.value)
+            } else if let Some(value) = record.get("vs").or_else(|| record.get("vb")).or_else(|| record.get("vd")) {
+                value.clone()
+            } else if let Some(sum) = record.get("s") {
+                add(sum, base.sum)
+            } else {
+                warn!("SenML record {} without a value is skipped", name);
+                continue;
+            };
+
+            let time = base.time + record.get("t").and_then(Value::as_f64).unwrap_or(0.0);
+            let unit = record.get("u").and_then(Value::as_str).unwrap_or(&base.unit);
+            measurements.push(Measurement { unit: self.unit(&name, record_name, unit), value, timestamp: timestamp(time, now) });
+        }
+        Ok(measurements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_base_fields_resolution() {
+        let mut config = SenmlConfig::default();
+        config.units.insert("urn:dev:ow:1:temp".to_string(), "temperature".to_string());
+        config.units.insert("temp:Far".to_string(), "temperature_f".to_string());
+        let now = Utc.timestamp(1_600_000_100, 0);
+        let pack = json!([
+            { "bn": "urn:dev:ow:1:", "bt": 1.6e9, "bv": 10, "n": "temp", "u": "Cel", "v": 11.5 },
+            { "n": "temp", "t": 60, "v": 12 },
+            { "n": "temp", "u": "Far", "v": 60 },
+            { "n": "door", "vb": true },
+            { "n": "label" },
+            { "bn": "urn:dev:ow:2:", "bt": -5, "n": "energy", "s": 7 },
+        ]);
+        let measurements = config.resolve(pack.as_array().unwrap().clone(), now).unwrap();
+        let resolved: Vec<(String, Value, i64)> = measurements.into_iter()
+            .map(|m| (m.unit, m.value, m.timestamp.unwrap().timestamp())).collect();
+        assert_eq!(resolved, vec![
+            ("temperature".to_string(), json!(21.5), 1_600_000_000),
+            ("temperature".to_string(), json!(22.0), 1_600_000_060),
+            ("temperature_f".to_string(), json!(70.0), 1_600_000_000),
+            ("door".to_string(), json!(true), 1_600_000_000),
+            ("energy".to_string(), json!(7), 1_600_000_095),
+        ]);
+    }
+
+    #[test]
+    fn test_cbor_labels() {
+        // [{-2: "dev:", 0: "temp", 2: 21.5}] decoded by payload::decode
+        let pack = json!([{ "-2": "dev:", "-4": "Cel", "0": "temp", "2": 21.5, "6": 0 }, { "0": "humidity", "2": 40 }]);
+        let now = Utc.timestamp(1_600_000_000, 0);
+        let mut config = SenmlConfig::default();
+        config.units.insert("temp:Cel".to_string(), "temperature".to_string());
+        let measurements = config.resolve(pack.as_array().unwrap().clone(), now).unwrap();
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(measurements[0].unit, "temperature");
+        assert_eq!(measurements[1].unit, "humidity");
+        assert_eq!(measurements[0].value, json!(21.5));
+        assert_eq!(measurements[0].timestamp, Some(now));
+        assert!(SenmlConfig::default().resolve(vec![json!(1)], now).is_err());
+    }
+}
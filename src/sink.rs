@@ -0,0 +1,407 @@
+//** Output sinks */
+//** Resolved measurements are routed by the processing threads to sinks: MySQL, Phoenix live push and sink threads. */
+//** Every route has its own queue and filter. A sink thread buffers records and writes them in batches; */
+//** failed batches are retried with backoff, the oldest records are dropped on overflow */
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use log::{info, warn, error};
+use crossbeam::channel;
+use chrono::prelude::*;
+use attohttpc::header::HeaderName;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::backoff::{Backoff, ReconnectPolicy};
+use super::feeder::{Ack, Command};
+use super::influx::InfluxConfig;
+use super::jsonl::JsonlConfig;
+use super::republish::RepublishConfig;
+use super::webhook::WebhookConfig;
+use super::metrics;
+
+/// A measurement of a device resolved by the storage stage
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub device_id: usize,
+    pub uid: String,
+    pub unit_id: usize,
+    pub unit: String,
+    pub value: Value,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Record {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "device_id": self.device_id,
+            "uid": self.uid,
+            "unit_id": self.unit_id,
+            "unit": self.unit,
+            "value": self.value,
+            "timestamp": self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        })
+    }
+}
+
+/// Lifecycle event: device_activated, device_deactivated, unit_created, alert (rejected message or value)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub kind: &'static str,
+    pub uid: Option<String>, // device the event is about
+    pub payload: Value,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Event {
+    pub fn new(kind: &'static str, uid: Option<String>, payload: Value) -> Self {
+        Event { kind, uid, payload, timestamp: Utc::now() }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": self.kind,
+            "uid": self.uid,
+            "payload": self.payload,
+            "timestamp": self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        })
+    }
+}
+
+/// Records of the route, empty lists match everything
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    /// Device UIDs
+    pub devices: Vec<String>,
+    /// Unit names
+    pub units: Vec<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        (self.devices.is_empty() || self.devices.contains(&record.uid))
+            && (self.units.is_empty() || self.units.contains(&record.unit))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkKind {
+    Mysql,
+    Phoenix,
+    Influx(InfluxConfig),
+    Jsonl(JsonlConfig),
+    Mqtt(RepublishConfig),
+    Webhook(WebhookConfig),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    #[serde(default)]
+    pub filter: Filter,
+}
+
+impl SinkKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SinkKind::Mysql => "mysql",
+            SinkKind::Phoenix => "phoenix",
+            SinkKind::Influx(_) => "influx",
+            SinkKind::Jsonl(_) => "jsonl",
+            SinkKind::Mqtt(_) => "mqtt",
+            SinkKind::Webhook(_) => "webhook",
+        }
+    }
+}
+
+/// Command a route's thread takes records in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Store,   // DBStorage thread, records of a message are stored at once and acknowledged
+    Live,    // Live Pusher thread
+    Records, // sink thread
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: &'static str,
+    pub filter: Filter,
+    pub target: Target,
+    /// The sink takes lifecycle events as well
+    pub events: bool,
+    pub sender: channel::Sender<Command>,
+}
+
+/// Send records of a message to every route. A route gets only records matching its filter.
+/// Return true if a Store command was queued, the message is acknowledged after the write then
+pub fn dispatch(routes: &[Route], device_id: usize, records: &[Record], ack: &Ack) -> bool {
+    let mut stored = false;
+    for route in routes {
+        let selected: Vec<&Record> = records.iter().filter(|record| route.filter.matches(record)).collect();
+        let message = match route.target {
+            _ if selected.is_empty() => continue,
+            Target::Store => {
+                stored = true;
+                Command::Store(device_id, selected.into_iter().cloned().collect(), ack.clone())
+            },
+            Target::Live => {
+                Command::Live(device_id, selected.iter().map(|record| (record.unit_id, record.unit.clone(), record.value.to_string())).collect())
+            },
+            Target::Records => Command::Records(selected.into_iter().cloned().collect()),
+        };
+        if let Err(error) = route.sender.send(message) {
+            error!("Cannot send records to sink {}: {}", route.name, error);
+        }
+        metrics::set(&format!("sink_queue_length{{sink=\"{}\"}}", route.name), route.sender.len() as u64);
+    }
+    stored
+}
+
+/// Send an event to routes taking events, events about a device are filtered by the device UID
+pub fn notify(routes: &[Route], event: Event) {
+    for route in routes.iter().filter(|route| route.events) {
+        if let Some(uid) = &event.uid {
+            if !route.filter.devices.is_empty() && !route.filter.devices.contains(uid) {
+                continue;
+            }
+        }
+        if let Err(error) = route.sender.send(Command::Event(event.clone())) {
+            error!("Cannot send event to sink {}: {}", route.name, error);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SinkError {
+    /// The batch could be written later: the endpoint is unavailable, IO error
+    Retry(String),
+    /// The batch is rejected and dropped
+    Drop(String),
+}
+
+pub trait Sink {
+    /// Name used in logs and metrics
+    fn name(&self) -> &'static str;
+    fn write(&mut self, records: &[Record]) -> Result<(), SinkError>;
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct BatchPolicy {
+    /// Max amount of records in one write
+    pub batch_size: usize,
+    /// Max delay before buffered records are written, in milliseconds
+    pub flush_interval: u64,
+    /// Max amount of buffered records, the oldest records are dropped
+    pub buffer_size: usize,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        BatchPolicy {
+            batch_size: 500,
+            flush_interval: 1000,
+            buffer_size: 100_000,
+        }
+    }
+}
+
+/// POST a body, return the status and the text of the response; only failures to send are errors
+pub fn http_post_response(url: &str, content_type: &str, headers: &[(String, String)], body: Vec<u8>, timeout: Duration) -> Result<(u16, String), SinkError> {
+    let mut request = attohttpc::post(url)
+        .timeout(timeout)
+        .header("Content-Type", content_type);
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|error| SinkError::Drop(error.to_string()))?;
+        request = request.header(name, value.as_str());
+    }
+    let response = request.bytes(body).send().map_err(|error| SinkError::Retry(error.to_string()))?;
+    let status = response.status().as_u16();
+    Ok((status, response.text().unwrap_or_default()))
+}
+
+/// Result of a response status. 4xx are not retried, except 429 and the statuses fixed on the endpoint side:
+/// 401 and 403 (a token), 404 (a bucket or a database)
+pub fn status_result(status: u16, text: String) -> Result<(), SinkError> {
+    match status {
+        200..=299 => Ok(()),
+        401 | 403 | 404 | 429 => Err(SinkError::Retry(format!("{}: {}", status, text))),
+        // the request would be rejected again
+        400..=499 => Err(SinkError::Drop(format!("{}: {}", status, text))),
+        _ => Err(SinkError::Retry(status.to_string())),
+    }
+}
+
+/// POST a body, see status_result for retried statuses
+pub fn http_post(url: &str, content_type: &str, headers: &[(String, String)], body: Vec<u8>, timeout: Duration) -> Result<(), SinkError> {
+    let (status, text) = http_post_response(url, content_type, headers, body, timeout)?;
+    status_result(status, text)
+}
+
+/// Write buffered records in batches, stop at the first batch to retry. Return false in that case
+fn flush(sink: &mut dyn Sink, buffer: &mut VecDeque<Record>, batch_size: usize) -> bool {
+    while !buffer.is_empty() {
+        let size = batch_size.min(buffer.len());
+        let result = sink.write(&buffer.make_contiguous()[..size]);
+        match result {
+            Ok(()) => {
+                metrics::add(&format!("sink_written_total{{sink=\"{}\"}}", sink.name()), size as u64);
+            },
+            Err(SinkError::Drop(reason)) => {
+                error!("Sink {} rejected {} records: {}", sink.name(), size, reason);
+                metrics::add(&format!("sink_dropped_total{{sink=\"{}\"}}", sink.name()), size as u64);
+            },
+            Err(SinkError::Retry(reason)) => {
+                warn!("Sink {} cannot write {} records: {}", sink.name(), size, reason);
+                return false;
+            }
+        }
+        buffer.drain(..size);
+    }
+    true
+}
+
+/// A sink thread, it exits on Disconnect or when all senders are dropped
+pub fn run(sink: &mut dyn Sink, receiver: channel::Receiver<Command>, batch: BatchPolicy, policy: ReconnectPolicy) {
+    info!("Sink {} is started", sink.name());
+    let batch_size = batch.batch_size.max(1);
+    let flush_interval = Duration::from_millis(batch.flush_interval);
+    let mut buffer: VecDeque<Record> = VecDeque::new();
+    let mut backoff = Backoff::new(sink.name(), policy);
+    let mut flush_at = Instant::now() + flush_interval;
+
+    loop {
+        let timeout = flush_at.saturating_duration_since(Instant::now());
+        let stop = match receiver.recv_timeout(timeout) {
+            Ok(Command::Records(records)) => {
+                buffer.extend(records);
+                if buffer.len() > batch.buffer_size {
+                    let dropped = buffer.len() - batch.buffer_size;
+                    warn!("Sink {} buffer is full, drop {} oldest records", sink.name(), dropped);
+                    metrics::add(&format!("sink_dropped_total{{sink=\"{}\"}}", sink.name()), dropped as u64);
+                    buffer.drain(..dropped);
+                }
+                false
+            },
+            Ok(Command::Disconnect) | Err(channel::RecvTimeoutError::Disconnected) => true,
+            Ok(message) => {
+                warn!("Sink {} got unimplemented message: {:?}", sink.name(), message);
+                false
+            },
+            Err(channel::RecvTimeoutError::Timeout) => false,
+        };
+
+        // a full batch is written at once unless the sink waits for a retry
+        let now = Instant::now();
+        if stop || now >= flush_at || (buffer.len() >= batch_size && backoff.attempt() == 0) {
+            if flush(sink, &mut buffer, batch_size) {
+                backoff.reset();
+                flush_at = now + flush_interval;
+            } else {
+                flush_at = now + backoff.next_delay();
+            }
+        }
+        if stop {
+            if !buffer.is_empty() {
+                error!("Sink {} is stopped, {} records are not written", sink.name(), buffer.len());
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use serde_json::json;
+
+    struct FlakySink {
+        failures: usize,
+        written: Vec<Value>,
+    }
+
+    impl Sink for FlakySink {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn write(&mut self, records: &[Record]) -> Result<(), SinkError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(SinkError::Retry("unavailable".to_string()));
+            }
+            self.written.extend(records.iter().map(|record| record.value.clone()));
+            Ok(())
+        }
+    }
+
+    fn record(value: Value) -> Record {
+        Record { device_id: 1, uid: "dev".to_string(), unit_id: 2, unit: "temp".to_string(), value, timestamp: Utc::now() }
+    }
+
+    #[test]
+    fn test_routes_get_filtered_records() {
+        let (db_sender, db_receiver) = channel::unbounded();
+        let (sink_sender, sink_receiver) = channel::unbounded();
+        let routes = vec![
+            Route { name: "mysql", filter: Filter::default(), target: Target::Store, events: false, sender: db_sender },
+            Route { name: "jsonl", filter: Filter { units: vec!["humidity".to_string()], ..Filter::default() }, target: Target::Records, events: false, sender: sink_sender },
+        ];
+        let humidity = Record { unit_id: 3, unit: "humidity".to_string(), ..record(json!(40)) };
+        let ack = Ack { uid: "dev".to_string(), message_id: json!(5), dedup_key: None };
+        assert!(dispatch(&routes, 1, &[record(json!(21.5)), humidity.clone()], &ack));
+        assert!(dispatch(&routes, 1, &[record(json!(22))], &ack));
+
+        match db_receiver.try_recv() {
+            Ok(Command::Store(1, values, _)) => assert_eq!(values.len(), 2),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(db_receiver.try_recv().is_ok());
+        match sink_receiver.try_recv() {
+            Ok(Command::Records(records)) => assert_eq!(records, vec![humidity]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(sink_receiver.try_recv().is_err());
+
+        // no record passes the filter of the Store route
+        let routes = vec![Route { filter: Filter { units: vec!["humidity".to_string()], ..Filter::default() }, ..routes[0].clone() }];
+        assert!(!dispatch(&routes, 1, &[record(json!(23))], &ack));
+        assert!(db_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_status_result() {
+        assert!(status_result(204, String::new()).is_ok());
+        assert!(matches!(status_result(400, "bad".to_string()), Err(SinkError::Drop(_))));
+        for status in &[401, 403, 404, 429, 503] {
+            assert!(matches!(status_result(*status, String::new()), Err(SinkError::Retry(_))));
+        }
+    }
+
+    #[test]
+    fn test_sink_config() {
+        let config: SinkConfig = serde_json::from_str(r#"{"type": "jsonl", "file": "out.jsonl", "filter": {"devices": ["dev"]}}"#).unwrap();
+        assert_eq!(config.kind.name(), "jsonl");
+        assert_eq!(config.filter.devices, vec!["dev".to_string()]);
+    }
+
+    #[test]
+    fn test_failed_batches_are_retried_in_order() {
+        let (sender, receiver) = channel::unbounded();
+        let handle = thread::spawn(move || {
+            let mut sink = FlakySink { failures: 2, written: Vec::new() };
+            let batch = BatchPolicy { batch_size: 2, flush_interval: 10, buffer_size: 10 };
+            let policy = ReconnectPolicy { initial_delay: 10, max_delay: 10, multiplier: 1.0, jitter: 0.0 };
+            run(&mut sink, receiver, batch, policy);
+            sink.written
+        });
+        sender.send(Command::Records(vec![record(json!(1)), record(json!(2)), record(json!(3))])).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        sender.send(Command::Disconnect).unwrap();
+        assert_eq!(handle.join().unwrap(), vec![json!(1), json!(2), json!(3)]);
+    }
+}
@@ -0,0 +1,458 @@
+//** Sparkplug B */
+//** Edge nodes publish protobuf payloads to spBv1.0/<group>/<type>/<edge>[/<device>]. Births (NBIRTH, DBIRTH) */
+//** announce metrics with their aliases and types, data messages may carry aliases only. The tracker keeps alias */
+//** tables and sequence numbers of every node and turns metrics into measurements of feeder devices. A node whose */
+//** aliases are unknown or whose sequence has a gap is asked to rebirth with an NCMD */
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use log::{info, warn};
+use chrono::prelude::*;
+use protobuf::{CodedInputStream, CodedOutputStream};
+use protobuf::rt::WireType;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::metrics;
+use super::payload::Measurement;
+
+pub const NAMESPACE: &str = "spBv1.0";
+/// Sparkplug datatype of booleans
+const BOOLEAN: u32 = 11;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SparkplugConfig {
+    /// Group id to subscribe to, + for all groups
+    pub group: String,
+    /// UID of a device, {group}, {edge} and {device} are replaced
+    pub device_uid: String,
+    /// UID of an edge node for node metrics, {group} and {edge} are replaced
+    pub node_uid: String,
+    /// UIDs by <group>/<edge>/<device> (or <group>/<edge> for nodes), used instead of the templates
+    pub devices: BTreeMap<String, String>,
+    /// Unit by metric name, metrics without a mapping keep their names
+    pub units: BTreeMap<String, String>,
+}
+
+impl Default for SparkplugConfig {
+    fn default() -> Self {
+        SparkplugConfig {
+            group: "+".to_string(),
+            device_uid: "{group}.{edge}.{device}".to_string(),
+            node_uid: "{group}.{edge}".to_string(),
+            devices: BTreeMap::new(),
+            units: BTreeMap::new(),
+        }
+    }
+}
+
+impl SparkplugConfig {
+    pub fn subscriptions(&self) -> Vec<String> {
+        vec![format!("{}/{}/+/+", NAMESPACE, self.group), format!("{}/{}/+/+/+", NAMESPACE, self.group)]
+    }
+
+    fn uid(&self, topic: &Topic) -> String {
+        let key = topic.scope();
+        if let Some(uid) = self.devices.get(&key) {
+            return uid.clone();
+        }
+        let template = if topic.device.is_some() { &self.device_uid } else { &self.node_uid };
+        template.replace("{group}", &topic.group).replace("{edge}", &topic.edge)
+            .replace("{device}", topic.device.as_deref().unwrap_or(""))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topic {
+    pub group: String,
+    pub kind: String,
+    pub edge: String,
+    pub device: Option<String>,
+}
+
+impl Topic {
+    /// spBv1.0/<group>/<type>/<edge>[/<device>]
+    pub fn parse(topic: &str) -> Option<Self> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        match levels.as_slice() {
+            [NAMESPACE, group, kind, edge] => Some(Topic { group: group.to_string(), kind: kind.to_string(), edge: edge.to_string(), device: None }),
+            [NAMESPACE, group, kind, edge, device] => Some(Topic { group: group.to_string(), kind: kind.to_string(), edge: edge.to_string(),
+                device: Some(device.to_string()) }),
+            _ => None,
+        }
+    }
+
+    fn node(&self) -> String {
+        format!("{}/{}", self.group, self.edge)
+    }
+
+    /// Command topic of the node
+    fn command(&self) -> String {
+        format!("{}/{}/NCMD/{}", NAMESPACE, self.group, self.edge)
+    }
+
+    /// Node or device the metrics belong to
+    fn scope(&self) -> String {
+        match &self.device {
+            Some(device) => format!("{}/{}/{}", self.group, self.edge, device),
+            None => self.node(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum RawValue {
+    Int(u32),
+    Long(u64),
+    Float(f32),
+    Double(f64),
+    Boolean(bool),
+    Text(String),
+    Bytes(Vec<u8>),
+    Unsupported,
+}
+
+#[derive(Debug, Default)]
+struct Metric {
+    name: Option<String>,
+    alias: Option<u64>,
+    timestamp: Option<u64>,
+    datatype: u32,
+    is_null: bool,
+    value: Option<RawValue>,
+}
+
+#[derive(Debug, Default)]
+struct Payload {
+    timestamp: Option<u64>,
+    metrics: Vec<Metric>,
+    seq: Option<u64>,
+}
+
+fn read_metric(bytes: &[u8]) -> protobuf::Result<Metric> {
+    let mut input = CodedInputStream::from_bytes(bytes);
+    let mut metric = Metric::default();
+    while let Some(tag) = input.read_raw_tag_or_eof()? {
+        match tag >> 3 {
+            1 => metric.name = Some(input.read_string()?),
+            2 => metric.alias = Some(input.read_uint64()?),
+            3 => metric.timestamp = Some(input.read_uint64()?),
+            4 => metric.datatype = input.read_uint32()?,
+            7 => metric.is_null = input.read_bool()?,
+            10 => metric.value = Some(RawValue::Int(input.read_uint32()?)),
+            11 => metric.value = Some(RawValue::Long(input.read_uint64()?)),
+            12 => metric.value = Some(RawValue::Float(input.read_float()?)),
+            13 => metric.value = Some(RawValue::Double(input.read_double()?)),
+            14 => metric.value = Some(RawValue::Boolean(input.read_bool()?)),
+            15 => metric.value = Some(RawValue::Text(input.read_string()?)),
+            16 => metric.value = Some(RawValue::Bytes(input.read_bytes()?)),
+            // datasets, templates and extensions
+            17..=19 => {
+                input.read_bytes()?;
+                metric.value = Some(RawValue::Unsupported);
+            },
+            _ => skip(&mut input, tag)?,
+        }
+    }
+    Ok(metric)
+}
+
+fn skip(input: &mut CodedInputStream, tag: u32) -> protobuf::Result<()> {
+    match WireType::new(tag & 7) {
+        Some(wire_type) => input.skip_field(wire_type),
+        None => Err(protobuf::Error::from(std::io::Error::new(std::io::ErrorKind::InvalidData, "unknown wire type"))),
+    }
+}
+
+fn read_payload(bytes: &[u8]) -> protobuf::Result<Payload> {
+    let mut input = CodedInputStream::from_bytes(bytes);
+    let mut payload = Payload::default();
+    while let Some(tag) = input.read_raw_tag_or_eof()? {
+        match tag >> 3 {
+            1 => payload.timestamp = Some(input.read_uint64()?),
+            2 => payload.metrics.push(read_metric(&input.read_bytes()?)?),
+            3 => payload.seq = Some(input.read_uint64()?),
+            _ => skip(&mut input, tag)?,
+        }
+    }
+    Ok(payload)
+}
+
+/// NCMD payload with the Node Control/Rebirth metric set to true
+pub fn rebirth_payload(now: DateTime<Utc>) -> protobuf::Result<Vec<u8>> {
+    let timestamp = now.timestamp_millis() as u64;
+    let mut metric = Vec::new();
+    {
+        let mut output = CodedOutputStream::vec(&mut metric);
+        output.write_string(1, "Node Control/Rebirth")?;
+        output.write_uint64(3, timestamp)?;
+        output.write_uint32(4, BOOLEAN)?;
+        output.write_bool(14, true)?;
+        output.flush()?;
+    }
+    let mut payload = Vec::new();
+    {
+        let mut output = CodedOutputStream::vec(&mut payload);
+        output.write_uint64(1, timestamp)?;
+        output.write_bytes(2, &metric)?;
+        output.flush()?;
+    }
+    Ok(payload)
+}
+
+/// JSON value of a metric by its Sparkplug datatype, None for datasets, templates and unknown types
+fn convert(datatype: u32, value: RawValue) -> Option<Value> {
+    match (datatype, value) {
+        // signed values are sent as unsigned of the same or a wider size
+        (1, RawValue::Int(number)) => Some(Value::from(number as u8 as i8)),
+        (2, RawValue::Int(number)) => Some(Value::from(number as u16 as i16)),
+        (3, RawValue::Int(number)) => Some(Value::from(number as i32)),
+        (4, RawValue::Long(number)) => Some(Value::from(number as i64)),
+        (_, RawValue::Int(number)) => Some(Value::from(number)),
+        (_, RawValue::Long(number)) => Some(Value::from(number)),
+        (_, RawValue::Float(number)) => serde_json::Number::from_f64(number as f64).map(Value::Number),
+        (_, RawValue::Double(number)) => serde_json::Number::from_f64(number).map(Value::Number),
+        (_, RawValue::Boolean(flag)) => Some(Value::Bool(flag)),
+        (_, RawValue::Text(text)) => Some(Value::String(text)),
+        (_, RawValue::Bytes(bytes)) => Some(Value::String(hex::encode(bytes))),
+        (_, RawValue::Unsupported) => None,
+    }
+}
+
+/// What the subscriber should do with a Sparkplug message
+#[derive(Debug, PartialEq)]
+pub enum Update {
+    /// Metrics of a device by UID
+    Measurements(String, Vec<Measurement>),
+    /// A node (with its devices) or a device is offline
+    Death(String),
+    /// Sequence number of a node is not the expected one: node UID, expected and received numbers
+    Gap(String, u64, u64),
+    /// NCMD topic of a node to ask for a rebirth
+    Rebirth(String),
+}
+
+#[derive(Default)]
+struct Scope {
+    names: HashMap<u64, String>,
+    datatypes: HashMap<String, u32>,
+}
+
+/// Alias tables and sequence numbers, the tracker outlives reconnections of the subscriber
+pub struct Tracker {
+    config: SparkplugConfig,
+    scopes: HashMap<String, Scope>,
+    sequences: HashMap<String, u64>,
+    /// Nodes asked to rebirth, a node is asked again only after its birth
+    rebirths: HashSet<String>,
+}
+
+impl Tracker {
+    pub fn new(config: SparkplugConfig) -> Self {
+        Tracker { config, scopes: HashMap::new(), sequences: HashMap::new(), rebirths: HashSet::new() }
+    }
+
+    fn rebirth(&mut self, topic: &Topic, updates: &mut Vec<Update>) {
+        if self.rebirths.insert(topic.node()) {
+            info!("Sparkplug node {}: rebirth requested", topic.node());
+            updates.push(Update::Rebirth(topic.command()));
+        }
+    }
+
+    fn check_sequence(&mut self, topic: &Topic, seq: Option<u64>, updates: &mut Vec<Update>) {
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return,
+        };
+        let node = topic.node();
+        if topic.kind != "NBIRTH" {
+            if let Some(last) = self.sequences.get(&node) {
+                let expected = (last + 1) % 256;
+                if seq != expected {
+                    warn!("Sparkplug node {}: sequence gap, expected {} got {}", node, expected, seq);
+                    metrics::increment("sparkplug_sequence_gaps_total");
+                    let node_topic = Topic { device: None, ..topic.clone() };
+                    updates.push(Update::Gap(self.config.uid(&node_topic), expected, seq));
+                    self.rebirth(topic, updates);
+                }
+            }
+        }
+        self.sequences.insert(node, seq);
+    }
+
+    /// Drop alias tables of a node and its devices, or of a device
+    fn forget(&mut self, topic: &Topic) {
+        let scope = topic.scope();
+        let prefix = format!("{}/", scope);
+        let node = topic.device.is_none();
+        self.scopes.retain(|key, _| key != &scope && !(node && key.starts_with(&prefix)));
+    }
+
+    pub fn handle(&mut self, topic: &Topic, bytes: &[u8], now: DateTime<Utc>) -> Result<Vec<Update>, String> {
+        let payload = read_payload(bytes).map_err(|error| error.to_string())?;
+        let mut updates = Vec::new();
+        let uid = self.config.uid(topic);
+        // NDEATH has no sequence number
+        self.check_sequence(topic, payload.seq, &mut updates);
+
+        match topic.kind.as_str() {
+            "NBIRTH" | "DBIRTH" => {
+                info!("Sparkplug birth of {}", topic.scope());
+                if topic.kind == "NBIRTH" {
+                    self.forget(topic);
+                    self.rebirths.remove(&topic.node());
+                }
+                let scope = self.scopes.entry(topic.scope()).or_default();
+                scope.names.clear();
+                scope.datatypes.clear();
+                for metric in &payload.metrics {
+                    if let Some(name) = &metric.name {
+                        if let Some(alias) = metric.alias {
+                            scope.names.insert(alias, name.clone());
+                        }
+                        scope.datatypes.insert(name.clone(), metric.datatype);
+                    }
+                }
+            },
+            "NDEATH" | "DDEATH" => {
+                info!("Sparkplug death of {}", topic.scope());
+                self.forget(topic);
+                if topic.kind == "NDEATH" {
+                    self.sequences.remove(&topic.node());
+                }
+                updates.push(Update::Death(uid));
+                return Ok(updates);
+            },
+            "NDATA" | "DDATA" => {},
+            // commands and states are not measurements
+            _ => return Ok(updates),
+        }
+
+        let scope = self.scopes.get(&topic.scope());
+        let mut measurements = Vec::new();
+        let mut unknown_alias = false;
+        for metric in payload.metrics {
+            let name = match metric.name.clone().or_else(|| metric.alias.and_then(|alias| scope.and_then(|scope| scope.names.get(&alias).cloned()))) {
+                Some(name) => name,
+                None => {
+                    warn!("Sparkplug {}: metric with unknown alias {:?} is dropped, waiting for a birth", topic.scope(), metric.alias);
+                    metrics::increment("sparkplug_unknown_aliases_total");
+                    unknown_alias = true;
+                    continue;
+                }
+            };
+            // bdSeq and node controls are session data
+            if name == "bdSeq" || name.starts_with("Node Control/") || metric.is_null {
+                continue;
+            }
+            let datatype = if metric.datatype != 0 {
+                metric.datatype
+            } else {
+                scope.and_then(|scope| scope.datatypes.get(&name).copied()).unwrap_or(0)
+            };
+            let value = match metric.value.and_then(|value| convert(datatype, value)) {
+                Some(value) => value,
+                None => continue,
+            };
+            let timestamp = metric.timestamp.or(payload.timestamp)
+                .and_then(|millis| Utc.timestamp_millis_opt(millis as i64).single())
+                .unwrap_or(now);
+            let unit = self.config.units.get(&name).cloned().unwrap_or(name);
+            measurements.push(Measurement { unit, value, timestamp: Some(timestamp) });
+        }
+        if !measurements.is_empty() {
+            updates.push(Update::Measurements(uid, measurements));
+        }
+        if unknown_alias {
+            self.rebirth(topic, &mut updates);
+        }
+        Ok(updates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn metric(name: Option<&str>, alias: u64, datatype: u32, value: f64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut output = CodedOutputStream::vec(&mut bytes);
+            if let Some(name) = name {
+                output.write_string(1, name).unwrap();
+            }
+            output.write_uint64(2, alias).unwrap();
+            if datatype != 0 {
+                output.write_uint32(4, datatype).unwrap();
+            }
+            output.write_double(13, value).unwrap();
+            output.flush().unwrap();
+        }
+        bytes
+    }
+
+    fn payload(seq: u64, metrics: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut output = CodedOutputStream::vec(&mut bytes);
+            output.write_uint64(1, 1_600_000_000_000).unwrap();
+            for metric in metrics {
+                output.write_bytes(2, metric).unwrap();
+            }
+            output.write_uint64(3, seq).unwrap();
+            output.flush().unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_topic() {
+        let topic = Topic::parse("spBv1.0/plant/DDATA/gw1/boiler").unwrap();
+        assert_eq!(topic.device.as_deref(), Some("boiler"));
+        assert_eq!(SparkplugConfig::default().uid(&topic), "plant.gw1.boiler");
+        assert_eq!(Topic::parse("spBv1.0/plant/NDATA/gw1").unwrap().device, None);
+        assert_eq!(Topic::parse("devices/1/data"), None);
+    }
+
+    #[test]
+    fn test_aliases_and_sequence() {
+        let mut config = SparkplugConfig::default();
+        config.units.insert("Temp".to_string(), "temperature".to_string());
+        let mut tracker = Tracker::new(config);
+        let now = Utc::now();
+        let node_birth = Topic::parse("spBv1.0/plant/NBIRTH/gw1").unwrap();
+        let birth = Topic::parse("spBv1.0/plant/DBIRTH/gw1/boiler").unwrap();
+        let data = Topic::parse("spBv1.0/plant/DDATA/gw1/boiler").unwrap();
+        let timestamp = Some(Utc.timestamp_millis(1_600_000_000_000));
+
+        // data before birth is dropped and the node is asked to rebirth once
+        let rebirth = Update::Rebirth("spBv1.0/plant/NCMD/gw1".to_string());
+        assert_eq!(tracker.handle(&data, &payload(5, &[metric(None, 1, 0, 20.0)]), now).unwrap(), vec![rebirth]);
+        assert_eq!(tracker.handle(&data, &payload(6, &[metric(None, 1, 0, 20.0)]), now).unwrap(), vec![]);
+
+        tracker.handle(&node_birth, &payload(0, &[]), now).unwrap();
+        let updates = tracker.handle(&birth, &payload(1, &[metric(Some("Temp"), 1, 10, 21.5)]), now).unwrap();
+        assert_eq!(updates, vec![Update::Measurements("plant.gw1.boiler".to_string(),
+            vec![Measurement { unit: "temperature".to_string(), value: json!(21.5), timestamp }])]);
+
+        let updates = tracker.handle(&data, &payload(3, &[metric(None, 1, 0, 22.0)]), now).unwrap();
+        assert_eq!(updates, vec![Update::Gap("plant.gw1".to_string(), 2, 3), Update::Rebirth("spBv1.0/plant/NCMD/gw1".to_string()),
+            Update::Measurements("plant.gw1.boiler".to_string(), vec![Measurement { unit: "temperature".to_string(), value: json!(22.0), timestamp }])]);
+
+        let death = Topic::parse("spBv1.0/plant/DDEATH/gw1/boiler").unwrap();
+        assert_eq!(tracker.handle(&death, &payload(4, &[]), now).unwrap(), vec![Update::Death("plant.gw1.boiler".to_string())]);
+        assert_eq!(tracker.handle(&data, &payload(5, &[metric(None, 1, 0, 23.0)]), now).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_rebirth_payload() {
+        let now = Utc.timestamp_millis(1_600_000_000_000);
+        let payload = read_payload(&rebirth_payload(now).unwrap()).unwrap();
+        assert_eq!(payload.timestamp, Some(1_600_000_000_000));
+        assert_eq!(payload.metrics.len(), 1);
+        assert_eq!(payload.metrics[0].name.as_deref(), Some("Node Control/Rebirth"));
+        assert_eq!(payload.metrics[0].datatype, BOOLEAN);
+        assert_eq!(payload.metrics[0].value, Some(RawValue::Boolean(true)));
+    }
+}
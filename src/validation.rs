@@ -0,0 +1,254 @@
+//** Validation of values */
+//** Per unit rules (overridden per device): allowed types, min/max bounds, sentinel values and max change */
+//** from the previous value. Rejected values are written to a rejection log as JSON lines */
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use log::{info, error};
+use chrono::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::metrics;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    Number,
+    String,
+    Boolean,
+    Array,
+    Object,
+}
+
+impl ValueType {
+    fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(_) => Some(ValueType::Number),
+            Value::String(_) => Some(ValueType::String),
+            Value::Bool(_) => Some(ValueType::Boolean),
+            Value::Array(_) => Some(ValueType::Array),
+            Value::Object(_) => Some(ValueType::Object),
+            Value::Null => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    /// Allowed types, any type if empty
+    pub types: Vec<ValueType>,
+    /// Bounds of numbers, values of other types are rejected if a bound is set
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Max difference from the previous accepted value of the device and unit
+    pub max_delta: Option<f64>,
+    /// Consecutive values rejected by max_delta after which the value is the new baseline (a real step change),
+    /// 0 to never reset
+    pub reset_after: u32,
+    /// Values sent by faulty sensors, e.g. -127 of a disconnected DS18B20
+    pub sentinels: Vec<Value>,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule {
+            types: Vec::new(),
+            min: None,
+            max: None,
+            max_delta: None,
+            reset_after: 3,
+            sentinels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    /// Rules by unit name
+    pub units: BTreeMap<String, Rule>,
+    /// Rules by device UID and unit name, they replace the unit rules
+    pub devices: BTreeMap<String, BTreeMap<String, Rule>>,
+    /// File the rejected values are appended to
+    pub log: Option<String>,
+}
+
+fn same(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl Rule {
+    /// Reason to reject the value
+    pub fn check(&self, value: &Value, previous: Option<f64>) -> Option<String> {
+        if self.sentinels.iter().any(|sentinel| same(sentinel, value)) {
+            return Some("sentinel value".to_string());
+        }
+        if !self.types.is_empty() && !matches!(ValueType::of(value), Some(kind) if self.types.contains(&kind)) {
+            return Some("type is not allowed".to_string());
+        }
+        if self.min.is_none() && self.max.is_none() && self.max_delta.is_none() {
+            return None;
+        }
+        let number = match value.as_f64() {
+            Some(number) => number,
+            None => return Some("not a number".to_string()),
+        };
+        if let Some(min) = self.min.filter(|min| number < *min) {
+            return Some(format!("less than {}", min));
+        }
+        if let Some(max) = self.max.filter(|max| number > *max) {
+            return Some(format!("greater than {}", max));
+        }
+        match (self.max_delta, previous) {
+            (Some(max_delta), Some(previous)) if (number - previous).abs() > max_delta => {
+                Some(format!("changed by more than {} from {}", max_delta, previous))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Last accepted value of a device unit and amount of values rejected by max_delta since then
+#[derive(Debug, Clone, Copy)]
+struct Baseline {
+    value: f64,
+    rejected: u32,
+}
+
+/// Rules with previous values of devices, shared by processing threads
+pub struct Validator {
+    config: ValidationConfig,
+    previous: Mutex<HashMap<(usize, String), Baseline>>,
+    log: Mutex<Option<File>>,
+}
+
+impl Validator {
+    pub fn new(config: ValidationConfig) -> Self {
+        let log = config.log.as_ref().and_then(|path| match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(file),
+            Err(error) => {
+                error!("Cannot open rejection log {}: {}", path, error);
+                None
+            }
+        });
+        Validator { config, previous: Mutex::new(HashMap::new()), log: Mutex::new(log) }
+    }
+
+    fn rule(&self, uid: &str, unit: &str) -> Option<&Rule> {
+        self.config.devices.get(uid).and_then(|rules| rules.get(unit)).or_else(|| self.config.units.get(unit))
+    }
+
+    /// Check a value of a device, the reason is returned (and logged) if the value is rejected
+    pub fn validate(&self, device_id: usize, uid: &str, unit: &str, value: &Value) -> Result<(), String> {
+        let rule = match self.rule(uid, unit) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        let key = (device_id, unit.to_string());
+        let mut previous = self.previous.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let baseline = previous.get(&key).copied();
+        // max_delta is checked after the other rules, so only its rejections count to reset the baseline
+        let reason = rule.check(value, None).map(|reason| (reason, false))
+            .or_else(|| rule.check(value, baseline.map(|baseline| baseline.value)).map(|reason| (reason, true)));
+        match (reason, value.as_f64()) {
+            (None, number) => {
+                if let (Some(_), Some(number)) = (rule.max_delta, number) {
+                    previous.insert(key, Baseline { value: number, rejected: 0 });
+                }
+                Ok(())
+            },
+            (Some((reason, true)), Some(number)) => {
+                let rejected = baseline.map_or(0, |baseline| baseline.rejected) + 1;
+                if rule.reset_after > 0 && rejected >= rule.reset_after {
+                    info!("Value {} of unit {} of device {} is the new baseline after {} rejections", number, unit, uid, rejected);
+                    previous.insert(key, Baseline { value: number, rejected: 0 });
+                    return Ok(());
+                }
+                previous.insert(key, Baseline { value: baseline.map_or(number, |baseline| baseline.value), rejected });
+                drop(previous);
+                self.reject(uid, unit, value, reason)
+            },
+            (Some((reason, _)), _) => {
+                drop(previous);
+                self.reject(uid, unit, value, reason)
+            }
+        }
+    }
+
+    fn reject(&self, uid: &str, unit: &str, value: &Value, reason: String) -> Result<(), String> {
+        metrics::increment("rejected_values_total");
+        self.log(uid, unit, value, &reason);
+        Err(reason)
+    }
+
+    fn log(&self, uid: &str, unit: &str, value: &Value, reason: &str) {
+        let mut log = self.log.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(file) = log.as_mut() {
+            let line = json!({ "uid": uid, "unit": unit, "value": value, "reason": reason,
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true) });
+            if let Err(error) = writeln!(file, "{}", line) {
+                error!("Cannot write rejection log: {}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        let rule = Rule { min: Some(-55.0), max: Some(125.0), max_delta: Some(10.0), sentinels: vec![json!(85)], ..Rule::default() };
+        assert_eq!(rule.check(&json!(-127), None), Some("less than -55".to_string()));
+        assert_eq!(rule.check(&json!(85.0), None), Some("sentinel value".to_string()));
+        assert_eq!(rule.check(&json!("21"), None), Some("not a number".to_string()));
+        assert_eq!(rule.check(&json!(21.5), Some(20.0)), None);
+        assert!(rule.check(&json!(40), Some(20.0)).is_some());
+
+        let typed = Rule { types: vec![ValueType::Boolean, ValueType::String], ..Rule::default() };
+        assert_eq!(typed.check(&json!(true), None), None);
+        assert!(typed.check(&json!(1), None).is_some());
+    }
+
+    #[test]
+    fn test_device_rules_and_previous_value() {
+        let mut config = ValidationConfig::default();
+        config.units.insert("temperature".to_string(), Rule { max_delta: Some(5.0), ..Rule::default() });
+        let mut device_rules = BTreeMap::new();
+        device_rules.insert("temperature".to_string(), Rule { max: Some(30.0), ..Rule::default() });
+        config.devices.insert("greenhouse".to_string(), device_rules);
+        let validator = Validator::new(config);
+
+        assert!(validator.validate(1, "boiler", "temperature", &json!(60)).is_ok());
+        assert!(validator.validate(1, "boiler", "temperature", &json!(70)).is_err());
+        // the rejected value is not the previous one
+        assert!(validator.validate(1, "boiler", "temperature", &json!(64)).is_ok());
+        assert!(validator.validate(2, "greenhouse", "temperature", &json!(60)).is_err());
+        assert!(validator.validate(2, "greenhouse", "humidity", &json!(60)).is_ok());
+    }
+
+    #[test]
+    fn test_step_change_resets_baseline() {
+        let mut config = ValidationConfig::default();
+        config.units.insert("pressure".to_string(), Rule { max_delta: Some(5.0), reset_after: 3, ..Rule::default() });
+        let validator = Validator::new(config);
+
+        assert!(validator.validate(1, "pump", "pressure", &json!(10)).is_ok());
+        // a spike is rejected, the next normal value resets the count
+        assert!(validator.validate(1, "pump", "pressure", &json!(50)).is_err());
+        assert!(validator.validate(1, "pump", "pressure", &json!(12)).is_ok());
+        // a step change is accepted after 3 consecutive rejections
+        assert!(validator.validate(1, "pump", "pressure", &json!(40)).is_err());
+        assert!(validator.validate(1, "pump", "pressure", &json!(41)).is_err());
+        assert!(validator.validate(1, "pump", "pressure", &json!(40)).is_ok());
+        assert!(validator.validate(1, "pump", "pressure", &json!(42)).is_ok());
+    }
+}
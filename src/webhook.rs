@@ -0,0 +1,287 @@
+//** Webhook sink */
+//** Batches of records and lifecycle events are POSTed as JSON {"records": [...], "events": [...]} to a partner service. */
+//** Bodies are signed with HMAC-SHA256; undelivered batches are kept in a spool file and retried in order with backoff */
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::mem;
+use std::time::{Duration, Instant};
+use log::{info, warn, error};
+use crossbeam::channel;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::backoff::{Backoff, ReconnectPolicy};
+use super::feeder::Command;
+use super::sink::{self, BatchPolicy, SinkError};
+use super::metrics;
+
+pub const TIMESTAMP_HEADER: &str = "X-Fennec-Timestamp";
+/// sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">
+pub const SIGNATURE_HEADER: &str = "X-Fennec-Signature";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Extra request headers, e.g. an API key
+    pub headers: BTreeMap<String, String>,
+    /// Key of the request signature, requests are not signed if not set
+    pub secret: Option<String>,
+    /// Send lifecycle events: device_activated, device_deactivated, unit_created, alert, device_offline, sequence_gap
+    pub events: bool,
+    /// Seconds to wait for the endpoint
+    pub timeout: u64,
+    pub batch: BatchPolicy,
+    /// File keeping undelivered batches between restarts, they are kept in memory only if not set
+    pub spool: Option<String>,
+    /// Max amount of undelivered batches, the oldest batches are dropped
+    pub spool_size: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            url: String::new(),
+            headers: BTreeMap::new(),
+            secret: None,
+            events: false,
+            timeout: 10,
+            batch: BatchPolicy::default(),
+            spool: None,
+            spool_size: 1000,
+        }
+    }
+}
+
+pub fn hmac_sha256_hex(secret: &str, data: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes a key of any size");
+    mac.update(data.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    format!("sha256={}", hmac_sha256_hex(secret, &format!("{}.{}", timestamp, body)))
+}
+
+/// Batches waiting for delivery. The file is append-only: a JSON body per pushed batch and a "-" line per removed one,
+/// it is rewritten with the waiting batches only once removed lines outnumber them
+pub struct Spool {
+    path: Option<String>,
+    size: usize,
+    batches: VecDeque<String>,
+    /// Lines appended to the file since it was written
+    appended: usize,
+}
+
+const REMOVED: &str = "-";
+
+impl Spool {
+    pub fn open(path: Option<String>, size: usize) -> Self {
+        let mut batches = VecDeque::new();
+        if let Some(content) = path.as_ref().and_then(|path| fs::read_to_string(path).ok()) {
+            for line in content.lines().filter(|line| !line.is_empty()) {
+                if line == REMOVED {
+                    batches.pop_front();
+                } else {
+                    batches.push_back(line.to_string());
+                }
+            }
+        }
+        let size = size.max(1);
+        while batches.len() > size {
+            batches.pop_front();
+        }
+        let mut spool = Spool { path, size, batches, appended: 0 };
+        spool.compact();
+        spool
+    }
+
+    /// Rewrite the file with the waiting batches only
+    fn compact(&mut self) {
+        if let Some(path) = &self.path {
+            let mut content = String::new();
+            for batch in &self.batches {
+                content.push_str(batch);
+                content.push('\n');
+            }
+            // replace the file at once, so a crash does not leave a half written spool
+            let temporary = format!("{}.tmp", path);
+            if let Err(error) = fs::write(&temporary, content).and_then(|_| fs::rename(&temporary, path)) {
+                error!("Webhook thread: cannot save spool {}: {}", path, error);
+            }
+        }
+        self.appended = 0;
+    }
+
+    fn append(&mut self, line: &str) {
+        if let Some(path) = &self.path {
+            let appended = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(error) = appended {
+                error!("Webhook thread: cannot save spool {}: {}", path, error);
+            }
+        }
+        self.appended += 1;
+        if self.appended > self.batches.len().max(100) {
+            self.compact();
+        }
+    }
+
+    pub fn push(&mut self, batch: String) {
+        self.append(&batch);
+        self.batches.push_back(batch);
+        if self.batches.len() > self.size {
+            warn!("Webhook spool is full, drop the oldest batch");
+            metrics::increment("webhook_dropped_total");
+            self.pop();
+        }
+    }
+
+    pub fn front(&self) -> Option<&String> {
+        self.batches.front()
+    }
+
+    pub fn pop(&mut self) {
+        if self.batches.pop_front().is_some() {
+            self.append(REMOVED);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+}
+
+fn deliver(config: &WebhookConfig, body: &str) -> Result<(), SinkError> {
+    let mut headers: Vec<(String, String)> = config.headers.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+    if let Some(secret) = &config.secret {
+        let timestamp = Utc::now().timestamp();
+        headers.push((TIMESTAMP_HEADER.to_string(), timestamp.to_string()));
+        headers.push((SIGNATURE_HEADER.to_string(), signature(secret, timestamp, body)));
+    }
+    sink::http_post(&config.url, "application/json", &headers, body.as_bytes().to_vec(), Duration::from_secs(config.timeout))
+}
+
+/// Webhook thread, it exits on Disconnect or when all senders are dropped
+pub fn run(config: &WebhookConfig, receiver: channel::Receiver<Command>, policy: ReconnectPolicy) {
+    let mut spool = Spool::open(config.spool.clone(), config.spool_size);
+    info!("Webhook sink is started, {} batches are waiting for delivery", spool.len());
+    let batch_size = config.batch.batch_size.max(1);
+    let flush_interval = Duration::from_millis(config.batch.flush_interval);
+    let mut records = Vec::new();
+    let mut events = Vec::new();
+    let mut backoff = Backoff::new("webhook", policy);
+    let mut flush_at = Instant::now() + flush_interval;
+    let mut retry_at = Instant::now();
+
+    loop {
+        let deadline = if spool.is_empty() { flush_at } else { flush_at.min(retry_at) };
+        let stop = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Command::Records(batch)) => {
+                records.extend(batch.iter().map(|record| record.to_json()));
+                false
+            },
+            Ok(Command::Event(event)) => {
+                events.push(event.to_json());
+                false
+            },
+            Ok(Command::Disconnect) | Err(channel::RecvTimeoutError::Disconnected) => true,
+            Ok(message) => {
+                warn!("Webhook thread: unimplemented message: {:?}", message);
+                false
+            },
+            Err(channel::RecvTimeoutError::Timeout) => false,
+        };
+
+        let now = Instant::now();
+        if stop || now >= flush_at || records.len() + events.len() >= batch_size {
+            if !records.is_empty() || !events.is_empty() {
+                spool.push(json!({ "records": mem::take(&mut records), "events": mem::take(&mut events) }).to_string());
+            }
+            flush_at = now + flush_interval;
+        }
+
+        // batches are delivered in order, a failed batch holds the next ones
+        while now >= retry_at {
+            let body = match spool.front() {
+                Some(body) => body.clone(),
+                None => break,
+            };
+            match deliver(config, &body) {
+                Ok(()) => {
+                    metrics::increment("webhook_delivered_total");
+                    backoff.reset();
+                    spool.pop();
+                },
+                Err(SinkError::Drop(reason)) => {
+                    error!("Webhook rejected the batch: {}", reason);
+                    metrics::increment("webhook_dropped_total");
+                    spool.pop();
+                },
+                Err(SinkError::Retry(reason)) => {
+                    warn!("Webhook thread: cannot deliver the batch: {}", reason);
+                    retry_at = now + backoff.next_delay();
+                }
+            }
+        }
+        metrics::set("webhook_spool_length", spool.len() as u64);
+
+        if stop {
+            if !spool.is_empty() {
+                warn!("Webhook sink is stopped, {} batches are not delivered", spool.len());
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231, test case 2
+        assert_eq!(hmac_sha256_hex("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(signature("Jefe", 1600000000, "{}"), format!("sha256={}", hmac_sha256_hex("Jefe", "1600000000.{}")));
+    }
+
+    #[test]
+    fn test_spool_survives_restart() {
+        let path = env::temp_dir().join(format!("ffeeder-spool-{}.jsonl", std::process::id())).to_string_lossy().to_string();
+        let mut spool = Spool::open(Some(path.clone()), 2);
+        spool.push("{\"records\":[1]}".to_string());
+        spool.push("{\"records\":[2]}".to_string());
+        spool.push("{\"records\":[3]}".to_string());
+
+        let mut spool = Spool::open(Some(path.clone()), 2);
+        assert_eq!(spool.len(), 2);
+        assert_eq!(spool.front().unwrap(), "{\"records\":[2]}");
+        spool.pop();
+        assert_eq!(Spool::open(Some(path.clone()), 2).front().unwrap(), "{\"records\":[3]}");
+
+        // batches are appended, the file is rewritten only when removed lines pile up
+        let mut spool = Spool::open(Some(path.clone()), 1000);
+        spool.pop();
+        for batch in 0..150 {
+            spool.push(format!("{{\"records\":[{}]}}", batch));
+            spool.pop();
+        }
+        spool.push("{\"records\":[\"last\"]}".to_string());
+        assert!(fs::read_to_string(&path).unwrap().lines().count() < 150);
+        let spool = Spool::open(Some(path.clone()), 1000);
+        assert_eq!((spool.len(), spool.front().unwrap().as_str()), (1, "{\"records\":[\"last\"]}"));
+        fs::remove_file(&path).unwrap();
+    }
+}